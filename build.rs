@@ -1,11 +1,30 @@
 
 extern crate cc;
 fn main() {
+    println!("cargo:rerun-if-changed=src/c");
+    println!("cargo:rerun-if-env-changed=THEOS");
+
+    // cargo can't pick default features per target, so without either feature, build for the target:
+    // `ios` on a device, `host` anywhere else
+    let mut ios = std::env::var_os("CARGO_FEATURE_IOS").is_some();
+    if !ios && std::env::var_os("CARGO_FEATURE_HOST").is_none() {
+        ios = std::env::var("CARGO_CFG_TARGET_OS").is_ok_and(|os| os == "ios");
+        println!("cargo:rustc-cfg=feature=\"{}\"", if ios { "ios" } else { "host" });
+    }
+
+    // the absd/MobileGestalt shim only builds against the iOS SDK
+    if !ios {
+        return;
+    }
 
     println!("cargo:rustc-link-lib=dylib=MobileGestalt");
 
-    cc::Build::new()
-        .archiver("/home/tae/theos/toolchain/linux/iphone/bin/ar")
+    let mut build = cc::Build::new();
+    // cross compiling from linux needs theos' archiver; otherwise cc's default (or $AR) is fine
+    if let Ok(theos) = std::env::var("THEOS") {
+        build.archiver(format!("{theos}/toolchain/linux/iphone/bin/ar"));
+    }
+    build
         .file("src/c/relay.c")
         .file("src/c/absdUser.c").compile("relay");
-}
\ No newline at end of file
+}
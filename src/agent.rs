@@ -0,0 +1,324 @@
+use std::{net::SocketAddr, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::Duration};
+
+use futures::{future::BoxFuture, FutureExt, SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{net::{TcpListener, TcpStream}, sync::Mutex};
+use tokio_tungstenite::{accept_hdr_async, connect_async, tungstenite::{client::IntoClientRequest, handshake::server::{ErrorResponse, Request, Response}, http::{HeaderValue, StatusCode}, Message}, MaybeTlsStream, WebSocketStream};
+
+use crate::{base64_decode, base64_encode, error::RelayError, nac::NacBackend};
+
+const AGENT_CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "kebab-case")]
+enum AgentRequest {
+    Init {
+        cert: String,
+    },
+    KeyEstablishment {
+        ctx: u64,
+        response: String,
+    },
+    Sign {
+        ctx: u64,
+        data: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum AgentReply {
+    Init {
+        ctx: u64,
+        request: String,
+    },
+    KeyEstablishment {},
+    Sign {
+        signature: String,
+    },
+    Error {
+        error: String,
+        nac_code: Option<i32>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct AgentMessage<T> {
+    id: u64,
+    #[serde(flatten)]
+    body: T,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AgentConfig {
+    pub bind: String,
+    /// Remote backends must present this as a bearer token
+    pub token: String,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        AgentConfig {
+            // the token travels in the clear over ws://, so only listen beyond loopback on purpose
+            bind: "127.0.0.1:7431".to_string(),
+            token: String::new(),
+        }
+    }
+}
+
+pub(crate) fn token_matches(given: &[u8], expected: &[u8]) -> bool {
+    // don't leak how much of the token was right through timing
+    given.len() == expected.len() && given.iter().zip(expected).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Serve this device's NAC backend to remote relayservers ("nac-agent" mode)
+pub async fn serve_agent(config: &AgentConfig, backend: Arc<dyn NacBackend>) -> Result<(), RelayError> {
+    if config.token.is_empty() {
+        return Err(RelayError::BadCommand("refusing to run the NAC agent without a token".to_string()))
+    }
+    // remote backends can't tell, so their relay's fake-backend guard would never see it
+    if backend.is_fake() {
+        return Err(RelayError::BadCommand("refusing to serve the fake NAC backend's signatures to other devices".to_string()))
+    }
+    let listener = TcpListener::bind(&config.bind).await?;
+    println!("NAC agent listening on {}", config.bind);
+    accept_agents(listener, &config.token, backend).await
+}
+
+async fn accept_agents(listener: TcpListener, token: &str, backend: Arc<dyn NacBackend>) -> Result<(), RelayError> {
+    let token = Arc::new(token.to_string());
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let backend = backend.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_agent_connection(stream, addr, &token, &*backend).await {
+                println!("NAC agent connection from {addr} failed: {err}");
+            }
+        });
+    }
+}
+
+async fn handle_agent_connection(stream: TcpStream, addr: SocketAddr, token: &str, backend: &dyn NacBackend) -> Result<(), RelayError> {
+    let expected = format!("Bearer {token}");
+    // tungstenite's callback signature, not ours to box
+    #[allow(clippy::result_large_err)]
+    let authorize = |request: &Request, response: Response| {
+        let authorized = request.headers().get("Authorization")
+            .is_some_and(|given| token_matches(given.as_bytes(), expected.as_bytes()));
+        if authorized {
+            Ok(response)
+        } else {
+            let mut error = ErrorResponse::new(Some("bad token".to_string()));
+            *error.status_mut() = StatusCode::UNAUTHORIZED;
+            Err(error)
+        }
+    };
+    let mut ws_stream = accept_hdr_async(stream, authorize).await?;
+    println!("NAC agent accepted {addr}");
+
+    while let Some(msg) = ws_stream.next().await {
+        let msg = match msg? {
+            Message::Text(msg) => msg,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let (id, body) = match serde_json::from_str::<AgentMessage<AgentRequest>>(&msg) {
+            Ok(call) => (call.id, answer_call(call.body, backend).await),
+            Err(err) => {
+                // answer under the id if there is one, so the caller fails now instead of timing out
+                let Some(id) = serde_json::from_str::<serde_json::Value>(&msg).ok().and_then(|msg| msg.get("id")?.as_u64()) else {
+                    println!("NAC agent got a bad message from {addr} without an id: {err}");
+                    continue
+                };
+                (id, Err(RelayError::NacAgentError(format!("bad request: {err}"))))
+            },
+        };
+        let body = match body {
+            Ok(reply) => reply,
+            Err(err) => AgentReply::Error {
+                error: err.to_string(),
+                nac_code: err.nac_code().map(|code| code.code()),
+            },
+        };
+        ws_stream.send(Message::Text(serde_json::to_string(&AgentMessage { id, body })?)).await?;
+    }
+    Ok(())
+}
+
+async fn answer_call(request: AgentRequest, backend: &dyn NacBackend) -> Result<AgentReply, RelayError> {
+    Ok(match request {
+        AgentRequest::Init { cert } => {
+            let (ctx, request) = backend.init(&base64_decode(&cert)?).await?;
+            AgentReply::Init { ctx, request: base64_encode(&request) }
+        },
+        AgentRequest::KeyEstablishment { ctx, response } => {
+            backend.key_establishment(ctx, &base64_decode(&response)?).await?;
+            AgentReply::KeyEstablishment {}
+        },
+        AgentRequest::Sign { ctx, data } => {
+            let signature = backend.sign(ctx, &base64_decode(&data)?).await?;
+            AgentReply::Sign { signature: base64_encode(&signature) }
+        },
+    })
+}
+
+/// Forwards NAC operations to a relayserver running in nac-agent mode on a device
+pub struct RemoteNacBackend {
+    url: String,
+    token: String,
+    conn: Mutex<Option<WebSocketStream<MaybeTlsStream<TcpStream>>>>,
+    next_id: AtomicU64,
+}
+
+impl RemoteNacBackend {
+    pub fn new(url: String, token: String) -> RemoteNacBackend {
+        RemoteNacBackend {
+            url,
+            token,
+            conn: Mutex::new(None),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    async fn connect(&self) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, RelayError> {
+        let mut request = self.url.as_str().into_client_request()?;
+        let auth = HeaderValue::from_str(&format!("Bearer {}", self.token))
+            .map_err(|_| RelayError::BadCommand("agent token is not a valid header".to_string()))?;
+        request.headers_mut().insert("Authorization", auth);
+        let (ws_stream, _) = connect_async(request).await?;
+        Ok(ws_stream)
+    }
+
+    async fn call(&self, request: AgentRequest) -> Result<AgentReply, RelayError> {
+        let mut conn = self.conn.lock().await;
+        let result = tokio::time::timeout(AGENT_CALL_TIMEOUT, async {
+            if conn.is_none() {
+                *conn = Some(self.connect().await?);
+            }
+            let ws_stream = conn.as_mut().unwrap();
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            ws_stream.send(Message::Text(serde_json::to_string(&AgentMessage { id, body: request })?)).await?;
+            loop {
+                let Some(msg) = ws_stream.next().await else {
+                    return Err(RelayError::NacAgentUnavailable("connection closed".to_string()))
+                };
+                let Message::Text(msg) = msg? else { continue };
+                let reply: AgentMessage<AgentReply> = serde_json::from_str(&msg)?;
+                // skip anything that isn't the reply to this call
+                if reply.id == id {
+                    return Ok(reply.body)
+                }
+            }
+        }).await.unwrap_or_else(|_| Err(RelayError::NacAgentUnavailable("timed out".to_string())));
+
+        match result {
+            Ok(AgentReply::Error { error, nac_code }) => Err(match nac_code {
+                Some(code) => RelayError::NacError(code.into()),
+                None => RelayError::NacAgentError(error),
+            }),
+            Ok(reply) => Ok(reply),
+            Err(err) => {
+                // whatever went wrong, the next call starts from a fresh connection
+                *conn = None;
+                Err(match err {
+                    RelayError::NacAgentUnavailable(_) => err,
+                    err => RelayError::NacAgentUnavailable(err.to_string()),
+                })
+            }
+        }
+    }
+}
+
+impl NacBackend for RemoteNacBackend {
+    fn init<'a>(&'a self, cert: &'a [u8]) -> BoxFuture<'a, Result<(u64, Vec<u8>), RelayError>> {
+        async move {
+            match self.call(AgentRequest::Init { cert: base64_encode(cert) }).await? {
+                AgentReply::Init { ctx, request } => Ok((ctx, base64_decode(&request)?)),
+                _ => Err(RelayError::NacAgentError("unexpected reply to init".to_string())),
+            }
+        }.boxed()
+    }
+
+    fn key_establishment<'a>(&'a self, ctx: u64, response: &'a [u8]) -> BoxFuture<'a, Result<(), RelayError>> {
+        async move {
+            match self.call(AgentRequest::KeyEstablishment { ctx, response: base64_encode(response) }).await? {
+                AgentReply::KeyEstablishment {} => Ok(()),
+                _ => Err(RelayError::NacAgentError("unexpected reply to key-establishment".to_string())),
+            }
+        }.boxed()
+    }
+
+    fn sign<'a>(&'a self, ctx: u64, data: &'a [u8]) -> BoxFuture<'a, Result<Vec<u8>, RelayError>> {
+        async move {
+            match self.call(AgentRequest::Sign { ctx, data: base64_encode(data) }).await? {
+                AgentReply::Sign { signature } => Ok(base64_decode(&signature)?),
+                _ => Err(RelayError::NacAgentError("unexpected reply to sign".to_string())),
+            }
+        }.boxed()
+    }
+
+    fn reset(&self) -> BoxFuture<'_, ()> {
+        async {
+            *self.conn.lock().await = None;
+        }.boxed()
+    }
+}
+
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::{connect_async, tungstenite::{client::IntoClientRequest, http::HeaderValue, Message}};
+
+    use crate::nac::{FakeNacBackend, NacBackend};
+
+    use super::{accept_agents, serve_agent, AgentConfig, AgentMessage, AgentReply, RemoteNacBackend};
+
+    // serve_agent itself won't serve the fake backend
+    async fn spawn_agent() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(accept_agents(listener, "sekrit", Arc::new(FakeNacBackend::default())));
+        url
+    }
+
+    #[tokio::test]
+    async fn refuses_to_serve_the_fake_backend() {
+        let config = AgentConfig { bind: "127.0.0.1:0".to_string(), token: "sekrit".to_string() };
+        assert!(serve_agent(&config, Arc::new(FakeNacBackend::default())).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn bad_messages_get_an_error_and_keep_the_connection() {
+        let url = spawn_agent().await;
+        let mut request = url.as_str().into_client_request().unwrap();
+        request.headers_mut().insert("Authorization", HeaderValue::from_static("Bearer sekrit"));
+        let (mut socket, _) = connect_async(request).await.unwrap();
+
+        socket.send(Message::Text("not json".to_string())).await.unwrap();
+        socket.send(Message::Text(r#"{"id":5,"method":"nope"}"#.to_string())).await.unwrap();
+        let reply: AgentMessage<AgentReply> = serde_json::from_str(&socket.next().await.unwrap().unwrap().into_text().unwrap()).unwrap();
+        assert_eq!(reply.id, 5);
+        assert!(matches!(reply.body, AgentReply::Error { nac_code: None, .. }));
+
+        socket.send(Message::Text(r#"{"id":6,"method":"sign","ctx":1,"data":""}"#.to_string())).await.unwrap();
+        let reply: AgentMessage<AgentReply> = serde_json::from_str(&socket.next().await.unwrap().unwrap().into_text().unwrap()).unwrap();
+        assert_eq!(reply.id, 6);
+        assert!(matches!(reply.body, AgentReply::Sign { .. }));
+    }
+
+    #[tokio::test]
+    async fn remote_backend_signs_through_the_agent() {
+        let url = spawn_agent().await;
+        let remote = RemoteNacBackend::new(url.clone(), "sekrit".to_string());
+        let (ctx, _) = remote.init(&[]).await.unwrap();
+        assert!(remote.sign(ctx, b"payload").await.unwrap().ends_with(b"payload"));
+
+        let wrong_token = RemoteNacBackend::new(url, "wrong".to_string());
+        assert!(wrong_token.init(&[]).await.unwrap_err().is_backend_lost());
+    }
+}
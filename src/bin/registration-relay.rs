@@ -0,0 +1,14 @@
+use relayserver::server::{RelayServer, RelayServerConfig};
+
+#[tokio::main]
+async fn main() {
+    let config_path = std::env::args().nth(1).unwrap_or_else(|| "relay-server.json".to_string());
+
+    let config = match std::fs::read_to_string(&config_path) {
+        Ok(read) => serde_json::from_str(&read).expect("Failed to parse relay server config"),
+        Err(_) => RelayServerConfig::default(),
+    };
+
+    let server = RelayServer::load(config).await.expect("Failed to load relay state");
+    server.serve().await.expect("Relay server failed");
+}
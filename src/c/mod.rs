@@ -1,6 +1,6 @@
-use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::{collections::HashMap, ffi::{c_char, c_int, c_void, CString}, fmt::Display, sync::{Mutex, OnceLock}};
 
-use crate::error::RelayError;
+use crate::{base64_encode, error::RelayError};
 
 
 
@@ -27,12 +27,19 @@ extern "C" {
         out_sig_len: *mut usize
     ) -> c_int;
 
+    fn nac_reset_port();
+
     fn mig_deallocate(
         data: *mut c_void,
         data_len: usize,
     );
     
-    fn mg_copy_answer(property: *const c_char) -> *mut c_char;
+    fn mg_copy_answer(
+        key: *const c_char,
+        out_type: *mut c_int,
+        out_data: *mut *mut c_void,
+        out_len: *mut usize
+    ) -> c_int;
 }
 
 pub fn nac_init_rs(cert: &[u8], output: &mut Vec<u8>) -> Result<u64, RelayError> {
@@ -46,7 +53,7 @@ pub fn nac_init_rs(cert: &[u8], output: &mut Vec<u8>) -> Result<u64, RelayError>
             mig_deallocate(out_req, out_req_cnt);
             Ok(ctx_out)
         } else {
-            Err(RelayError::NacError(resp as u64))
+            Err(RelayError::NacError(resp.into()))
         }
     }
 }
@@ -57,7 +64,7 @@ pub fn nac_key_establishment_rs(ctx: u64, response: &[u8]) -> Result<(), RelayEr
         if resp == 0 {
             Ok(())
         } else {
-            Err(RelayError::NacError(resp as u64))
+            Err(RelayError::NacError(resp.into()))
         }
     }
 }
@@ -72,17 +79,75 @@ pub fn nac_sign_rs(ctx: u64, data: &[u8]) -> Result<Vec<u8>, RelayError> {
             mig_deallocate(out_sig, out_sig_cnt);
             Ok(vec)
         } else {
-            Err(RelayError::NacError(resp as u64))
+            Err(RelayError::NacError(resp.into()))
         }
     }
 }
 
-pub fn mg_copy_answer_rs(item: &str) -> String {
+pub fn nac_reset_port_rs() {
     unsafe {
-        let c_str = CString::new(item).unwrap();
-        let answer = mg_copy_answer(c_str.as_ptr());
-        let c_str = CStr::from_ptr(answer).to_str().unwrap().to_string();
-        libc::free(answer as *mut c_void);
-        c_str
+        nac_reset_port();
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MgValue {
+    String(String),
+    Number(i64),
+    Float(f64),
+    Bool(bool),
+    Data(Vec<u8>),
+}
+
+impl Display for MgValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MgValue::String(value) => write!(f, "{value}"),
+            MgValue::Number(value) => write!(f, "{value}"),
+            MgValue::Float(value) => write!(f, "{value}"),
+            MgValue::Bool(value) => write!(f, "{value}"),
+            MgValue::Data(value) => write!(f, "{}", base64_encode(value)),
+        }
     }
 }
+
+// answers don't change while we're running, and protected keys are slow to ask for
+static MG_CACHE: OnceLock<Mutex<HashMap<String, MgValue>>> = OnceLock::new();
+
+pub fn mg_copy_answer_rs(item: &str) -> Result<MgValue, RelayError> {
+    let cache = MG_CACHE.get_or_init(Default::default);
+    if let Some(value) = cache.lock().unwrap().get(item) {
+        return Ok(value.clone())
+    }
+
+    let error = |reason: &str| RelayError::GestaltError(item.to_string(), reason.to_string());
+    let key = CString::new(item).map_err(|_| error("key contains a NUL byte"))?;
+    let value = unsafe {
+        let mut out_type: c_int = 0;
+        let mut out_data: *mut c_void = std::ptr::null_mut();
+        let mut out_len: usize = 0;
+        let resp = mg_copy_answer(key.as_ptr(), &mut out_type, &mut out_data, &mut out_len);
+        if resp != 0 {
+            return Err(error(match resp {
+                1 => "no answer (missing or not entitled to this protected key)",
+                2 => "answer has an unsupported type",
+                3 => "answer could not be converted",
+                4 => "key could not be converted",
+                _ => "unknown failure",
+            }))
+        }
+        let bytes = std::slice::from_raw_parts(out_data as *const u8, out_len).to_vec();
+        libc::free(out_data);
+        match out_type {
+            1 => MgValue::String(String::from_utf8(bytes).map_err(|_| error("answer is not UTF-8"))?),
+            2 => MgValue::Number(i64::from_ne_bytes(bytes.try_into().map_err(|_| error("bad number size"))?)),
+            3 => MgValue::Float(f64::from_ne_bytes(bytes.try_into().map_err(|_| error("bad number size"))?)),
+            4 => MgValue::Bool(bytes.first().is_some_and(|b| *b != 0)),
+            5 => MgValue::Data(bytes),
+            _ => return Err(error("unknown answer type")),
+        }
+    };
+
+    cache.lock().unwrap().insert(item.to_string(), value.clone());
+    Ok(value)
+}
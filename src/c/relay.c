@@ -7,6 +7,7 @@
 #import <sys/sysctl.h>
 #import <stdio.h>
 #import <stdlib.h>
+#import <string.h>
 #import <CoreFoundation/CoreFoundation.h>
 
 extern kern_return_t bootstrap_look_up(mach_port_t bp, const char *service_name, mach_port_t *sp);
@@ -15,20 +16,51 @@ mach_port_t ABSD_PORT = MACH_PORT_NULL;
 
 uint32_t NAC_MAGIC = 0x50936603;
 
-int nac_init(const void *certificate_bytes, size_t certificate_len, uint64_t *out_ctx, void **out_session_request, size_t *session_requestCnt) {
+// drop the cached port so the next call looks absd up again (e.g. after launchd restarted it)
+void nac_reset_port(void) {
+    if (ABSD_PORT != MACH_PORT_NULL) {
+        mach_port_deallocate(mach_task_self(), ABSD_PORT);
+        ABSD_PORT = MACH_PORT_NULL;
+    }
+}
+
+static kern_return_t absd_port(mach_port_t *out_port) {
     if (ABSD_PORT == MACH_PORT_NULL) {
         kern_return_t kret = bootstrap_look_up(bootstrap_port, "com.apple.absd", &ABSD_PORT);
         if (kret != KERN_SUCCESS) {
-            printf("bootstrap_look_up failed\n");
+            printf("bootstrap_look_up failed: %d\n", kret);
+            ABSD_PORT = MACH_PORT_NULL;
             return kret;
         }
     }
+    *out_port = ABSD_PORT;
+    return KERN_SUCCESS;
+}
+
+static int is_port_death(int ret) {
+    return ret == MACH_SEND_INVALID_DEST || ret == MACH_SEND_INVALID_RIGHT || ret == MACH_RCV_PORT_DIED || ret == MIG_SERVER_DIED;
+}
+
+static int check_port(int ret) {
+    if (is_port_death(ret)) {
+        printf("absd port died (%d), dropping it\n", ret);
+        nac_reset_port();
+    }
+    return ret;
+}
+
+int nac_init(const void *certificate_bytes, size_t certificate_len, uint64_t *out_ctx, void **out_session_request, size_t *session_requestCnt) {
+    mach_port_t port;
+    kern_return_t kret = absd_port(&port);
+    if (kret != KERN_SUCCESS) {
+        return kret;
+    }
     
     // endianness? what's that?
-    int ret = rawNACInit(ABSD_PORT, NAC_MAGIC, (vm_offset_t)certificate_bytes, certificate_len, out_ctx, (vm_offset_t *)out_session_request, (mach_msg_type_number_t *)session_requestCnt);
+    int ret = rawNACInit(port, NAC_MAGIC, (vm_offset_t)certificate_bytes, certificate_len, out_ctx, (vm_offset_t *)out_session_request, (mach_msg_type_number_t *)session_requestCnt);
     if (ret != 0) {
         printf("remoteNACInit failed: %d\n", ret);
-        return ret;
+        return check_port(ret);
     }
     printf("done\n");
 
@@ -36,28 +68,109 @@ int nac_init(const void *certificate_bytes, size_t certificate_len, uint64_t *ou
 }
 
 int nac_key_establishment(uint64_t val_ctx, const void *session_response, size_t session_response_len) {
-    return rawNACKeyEstablishment(ABSD_PORT, NAC_MAGIC, val_ctx, (vm_offset_t)session_response, session_response_len);
+    mach_port_t port;
+    kern_return_t kret = absd_port(&port);
+    if (kret != KERN_SUCCESS) {
+        return kret;
+    }
+    return check_port(rawNACKeyEstablishment(port, NAC_MAGIC, val_ctx, (vm_offset_t)session_response, session_response_len));
 }
 
 int nac_sign(uint64_t val_ctx, const void* data, size_t data_len, void **out_signature, size_t* out_sig_len) {
-    int ret = rawNACSign(ABSD_PORT, NAC_MAGIC, val_ctx, (vm_offset_t)data, data_len, (vm_offset_t *)out_signature, (mach_msg_type_number_t *)out_sig_len);
+    mach_port_t port;
+    kern_return_t kret = absd_port(&port);
+    if (kret != KERN_SUCCESS) {
+        return kret;
+    }
+    int ret = rawNACSign(port, NAC_MAGIC, val_ctx, (vm_offset_t)data, data_len, (vm_offset_t *)out_signature, (mach_msg_type_number_t *)out_sig_len);
     if (ret != 0) {
         printf("remoteNACSign failed: %d\n", ret);
-        return ret;
+        return check_port(ret);
     }
     return 0;
 }
 
 extern CFTypeRef MGCopyAnswer(CFStringRef property);
 
-char* mg_copy_answer(const char* firstProperty) {
-    CFStringRef property = CFStringCreateWithCString(kCFAllocatorDefault, firstProperty, kCFStringEncodingUTF8);
+// keep in sync with mg_copy_answer_rs in mod.rs
+enum {
+    MG_TYPE_STRING = 1,
+    MG_TYPE_NUMBER = 2,
+    MG_TYPE_FLOAT = 3,
+    MG_TYPE_BOOL = 4,
+    MG_TYPE_DATA = 5,
+};
+
+enum {
+    MG_OK = 0,
+    MG_ERR_NO_ANSWER = 1,
+    MG_ERR_UNSUPPORTED_TYPE = 2,
+    MG_ERR_CONVERSION = 3,
+    MG_ERR_BAD_KEY = 4,
+};
+
+static int mg_copy_bytes(const void *bytes, size_t len, void **out_data, size_t *out_len) {
+    void *buf = malloc(len ? len : 1);
+    if (buf == NULL) {
+        return MG_ERR_CONVERSION;
+    }
+    memcpy(buf, bytes, len);
+    *out_data = buf;
+    *out_len = len;
+    return MG_OK;
+}
+
+// answers are returned as a malloc'd buffer the caller frees; numbers are native-endian int64/double
+int mg_copy_answer(const char *key, int *out_type, void **out_data, size_t *out_len) {
+    *out_data = NULL;
+    *out_len = 0;
+
+    CFStringRef property = CFStringCreateWithCString(kCFAllocatorDefault, key, kCFStringEncodingUTF8);
+    if (property == NULL) {
+        return MG_ERR_BAD_KEY;
+    }
     CFTypeRef answer = MGCopyAnswer(property);
     CFRelease(property);
-    
-    size_t malloc_size = 128;
-    char *buildNumberBuf = calloc(1, malloc_size);
-    CFStringGetCString(answer, buildNumberBuf, malloc_size, kCFStringEncodingUTF8);
+    if (answer == NULL) {
+        return MG_ERR_NO_ANSWER;
+    }
+
+    int ret;
+    CFTypeID type = CFGetTypeID(answer);
+    if (type == CFStringGetTypeID()) {
+        CFIndex max_size = CFStringGetMaximumSizeForEncoding(CFStringGetLength(answer), kCFStringEncodingUTF8) + 1;
+        char *buf = calloc(1, max_size);
+        if (buf != NULL && CFStringGetCString(answer, buf, max_size, kCFStringEncodingUTF8)) {
+            *out_type = MG_TYPE_STRING;
+            *out_data = buf;
+            *out_len = strlen(buf);
+            ret = MG_OK;
+        } else {
+            free(buf);
+            ret = MG_ERR_CONVERSION;
+        }
+    } else if (type == CFBooleanGetTypeID()) {
+        uint8_t value = CFBooleanGetValue(answer) ? 1 : 0;
+        *out_type = MG_TYPE_BOOL;
+        ret = mg_copy_bytes(&value, sizeof(value), out_data, out_len);
+    } else if (type == CFNumberGetTypeID()) {
+        if (CFNumberIsFloatType(answer)) {
+            double value = 0;
+            CFNumberGetValue(answer, kCFNumberDoubleType, &value);
+            *out_type = MG_TYPE_FLOAT;
+            ret = mg_copy_bytes(&value, sizeof(value), out_data, out_len);
+        } else {
+            int64_t value = 0;
+            CFNumberGetValue(answer, kCFNumberSInt64Type, &value);
+            *out_type = MG_TYPE_NUMBER;
+            ret = mg_copy_bytes(&value, sizeof(value), out_data, out_len);
+        }
+    } else if (type == CFDataGetTypeID()) {
+        *out_type = MG_TYPE_DATA;
+        ret = mg_copy_bytes(CFDataGetBytePtr(answer), CFDataGetLength(answer), out_data, out_len);
+    } else {
+        ret = MG_ERR_UNSUPPORTED_TYPE;
+    }
     CFRelease(answer);
-    return buildNumberBuf;
+    return ret;
 }
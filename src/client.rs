@@ -0,0 +1,195 @@
+//! Bridge-side client for asking a provider for validation data through a registration relay.
+
+use std::time::Duration;
+
+use backon::{ExponentialBuilder, Retryable};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{base64_decode, base64_encode, protocol::{CommandData, ProviderStatus, RelayVersions}};
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("HTTP error: {0}")]
+    RequestError(#[from] reqwest::Error),
+    #[error("Relay returned {status}: {error}")]
+    RelayError {
+        status: StatusCode,
+        error: String,
+    },
+    #[error("Provider failed: {error}")]
+    ProviderError {
+        error: String,
+        nac_code: Option<i32>,
+        transient: bool,
+    },
+    #[error("Unexpected response to {0}")]
+    UnexpectedResponse(String),
+    #[error("Base64 error: {0}")]
+    Base64Error(#[from] base64::DecodeError),
+}
+
+impl ClientError {
+    /// Whether asking again later may succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ClientError::RequestError(err) => err.is_timeout() || err.is_connect() || err.is_request(),
+            // provider offline or slow, or the relay itself having trouble
+            ClientError::RelayError { status, .. } => status.is_server_error(),
+            ClientError::ProviderError { transient, .. } => *transient,
+            ClientError::UnexpectedResponse(_) | ClientError::Base64Error(_) => false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RelayErrorBody {
+    error: String,
+}
+
+pub struct RelayClient {
+    base_url: String,
+    code: String,
+    http: reqwest::Client,
+    retries: usize,
+}
+
+impl RelayClient {
+    /// `base_url` is the relay root, e.g. `https://registration-relay.beeper.com`
+    pub fn new(base_url: &str, code: &str) -> RelayClient {
+        RelayClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            code: code.to_string(),
+            http: Self::http_client(Duration::from_secs(60)),
+            retries: 3,
+        }
+    }
+
+    /// Like `new`, but first checks that the relay knows the code and the provider is online and
+    /// passing its self-test.
+    /// That lookup (`GET /api/v1/providers/:code`) is only served by our own `server::RelayServer`;
+    /// use `new` for other relays.
+    pub async fn connect(base_url: &str, code: &str) -> Result<RelayClient, ClientError> {
+        let client = RelayClient::new(base_url, code);
+        let status = client.provider_status().await?;
+        if !status.online {
+            return Err(ClientError::RelayError { status: StatusCode::SERVICE_UNAVAILABLE, error: "Provider is offline".to_string() })
+        }
+        if !status.ready {
+            return Err(ClientError::RelayError { status: StatusCode::SERVICE_UNAVAILABLE, error: "Provider is failing its self-test".to_string() })
+        }
+        Ok(client)
+    }
+
+    fn http_client(timeout: Duration) -> reqwest::Client {
+        reqwest::Client::builder()
+            .use_rustls_tls()
+            .timeout(timeout)
+            .build()
+            .unwrap()
+    }
+
+    /// Per-attempt timeout, covering the relay's round trip to the provider
+    pub fn with_timeout(mut self, timeout: Duration) -> RelayClient {
+        self.http = Self::http_client(timeout);
+        self
+    }
+
+    /// How many times transient failures are retried, with exponential backoff
+    pub fn with_retries(mut self, retries: usize) -> RelayClient {
+        self.retries = retries;
+        self
+    }
+
+    async fn check(response: reqwest::Response) -> Result<reqwest::Response, ClientError> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response)
+        }
+        let body = response.text().await?;
+        let error = serde_json::from_str::<RelayErrorBody>(&body).map(|body| body.error).unwrap_or(body);
+        Err(ClientError::RelayError { status, error })
+    }
+
+    pub async fn provider_status(&self) -> Result<ProviderStatus, ClientError> {
+        let response = self.http.get(format!("{}/api/v1/providers/{}", self.base_url, self.code)).send().await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    async fn command_once(&self, command: &str, data: Option<&CommandData>) -> Result<CommandData, ClientError> {
+        let mut request = self.http.post(format!("{}/api/v1/bridge/{command}", self.base_url))
+            .bearer_auth(&self.code);
+        if let Some(data) = data {
+            request = request.json(data);
+        }
+        let response = Self::check(request.send().await?).await?;
+        match response.json::<Option<CommandData>>().await? {
+            Some(CommandData::Error { error, nac_code, transient }) => Err(ClientError::ProviderError { error, nac_code, transient }),
+            Some(data) => Ok(data),
+            None => Err(ClientError::UnexpectedResponse(command.to_string())),
+        }
+    }
+
+    /// Send any command to the provider, retrying transient failures
+    pub async fn command(&self, command: &str, data: Option<CommandData>) -> Result<CommandData, ClientError> {
+        (|| self.command_once(command, data.as_ref()))
+            .retry(&ExponentialBuilder::default().with_jitter().with_max_times(self.retries))
+            .when(|err| err.is_transient())
+            .await
+    }
+
+    pub async fn get_version_info(&self) -> Result<RelayVersions, ClientError> {
+        match self.command("get-version-info", None).await? {
+            CommandData::Versions { versions } => Ok(versions),
+            _ => Err(ClientError::UnexpectedResponse("get-version-info".to_string())),
+        }
+    }
+
+    pub async fn get_validation_data(&self) -> Result<Vec<u8>, ClientError> {
+        match self.command("get-validation-data", None).await? {
+            CommandData::ValidationData { data } => Ok(base64_decode(&data)?),
+            _ => Err(ClientError::UnexpectedResponse("get-validation-data".to_string())),
+        }
+    }
+
+    pub async fn sign_payload(&self, payload: &[u8]) -> Result<Vec<u8>, ClientError> {
+        match self.command("sign-payload", Some(CommandData::Payload { payload: base64_encode(payload) })).await? {
+            CommandData::Signature { signature } => Ok(base64_decode(&signature)?),
+            _ => Err(ClientError::UnexpectedResponse("sign-payload".to_string())),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use reqwest::StatusCode;
+
+    use crate::testing::{fake_handler, fixture_device_info, spawn_relay_server, start_provider, TempDir};
+
+    use super::{ClientError, RelayClient};
+
+    #[tokio::test]
+    async fn connect_checks_the_provider() {
+        let dir = TempDir::new("client");
+        let (_server, base_url) = spawn_relay_server(&dir).await;
+
+        let unknown = RelayClient::connect(&base_url, "NOPE").await;
+        assert!(matches!(&unknown, Err(ClientError::RelayError { status: StatusCode::NOT_FOUND, .. })));
+        assert!(!unknown.err().unwrap().is_transient());
+
+        let (relay, code) = start_provider(&base_url, fake_handler(fixture_device_info())).await;
+        let client = RelayClient::connect(&format!("{base_url}/"), &code).await.unwrap();
+        assert_eq!(client.get_version_info().await.unwrap().hardware_version, "iPhone10,1");
+
+        relay.shutdown().await;
+        let offline = loop {
+            match RelayClient::connect(&base_url, &code).await {
+                Ok(_) => tokio::task::yield_now().await,
+                Err(err) => break err,
+            }
+        };
+        assert!(matches!(offline, ClientError::RelayError { status: StatusCode::SERVICE_UNAVAILABLE, .. }));
+        assert!(offline.is_transient());
+    }
+}
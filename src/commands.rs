@@ -0,0 +1,96 @@
+//! Answers to provider commands, shared by the relay connection and the local API.
+
+use std::sync::Arc;
+
+use tokio::{sync::broadcast, time::Instant};
+
+use crate::{base64_decode, base64_encode, device::DeviceInfoProvider, error::RelayError, events::RelayEvent, nac::{generate_validation_data, sign_payload, NacBackend}, protocol::{CommandData, RelayCommand}};
+
+pub fn error_data(err: &RelayError) -> CommandData {
+    CommandData::Error {
+        error: err.to_string(),
+        nac_code: err.nac_code().map(|code| code.code()),
+        transient: err.is_transient(),
+    }
+}
+
+pub struct CommandHandler {
+    pub nac: Arc<dyn NacBackend>,
+    pub device_info: Arc<dyn DeviceInfoProvider>,
+    pub events: broadcast::Sender<RelayEvent>,
+}
+
+impl CommandHandler {
+    pub fn new(nac: Arc<dyn NacBackend>, device_info: Arc<dyn DeviceInfoProvider>, events: broadcast::Sender<RelayEvent>) -> CommandHandler {
+        CommandHandler { nac, device_info, events }
+    }
+
+    /// The response to `command`, or `None` if it isn't one we answer
+    pub async fn handle(&self, command: &RelayCommand) -> Option<CommandData> {
+        Some(match command.command.as_str() {
+            "get-version-info" => match self.device_info.versions() {
+                Ok(versions) => CommandData::Versions { versions },
+                Err(err) => {
+                    println!("Failed to read version info: {err}");
+                    error_data(&err)
+                }
+            },
+            "get-validation-data" => {
+                println!("Generating validation data!");
+                let started = Instant::now();
+                match generate_validation_data(&*self.nac).await {
+                    Ok(data) => {
+                        let _ = self.events.send(RelayEvent::ValidationServed { id: command.id, latency: started.elapsed() });
+                        CommandData::ValidationData { data: base64_encode(&data) }
+                    },
+                    Err(err) => {
+                        println!("Failed to generate validation data: {err}");
+                        let _ = self.events.send(RelayEvent::ValidationFailed { id: command.id, error: err.to_string() });
+                        error_data(&err)
+                    }
+                }
+            },
+            "sign-payload" => {
+                let started = Instant::now();
+                match self.sign_command(command).await {
+                    Ok(signature) => {
+                        let _ = self.events.send(RelayEvent::ValidationServed { id: command.id, latency: started.elapsed() });
+                        CommandData::Signature { signature: base64_encode(&signature) }
+                    },
+                    Err(err) => {
+                        println!("Failed to sign payload: {err}");
+                        let _ = self.events.send(RelayEvent::ValidationFailed { id: command.id, error: err.to_string() });
+                        error_data(&err)
+                    }
+                }
+            },
+            _ => return None,
+        })
+    }
+
+    async fn sign_command(&self, command: &RelayCommand) -> Result<Vec<u8>, RelayError> {
+        let Some(CommandData::Payload { payload }) = &command.data else {
+            return Err(RelayError::BadCommand("sign-payload needs a payload".to_string()))
+        };
+        sign_payload(&*self.nac, &base64_decode(payload)?).await
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use crate::{events::RelayEvent, protocol::{CommandData, RelayCommand}, testing::{fake_handler, fixture_device_info}};
+
+    #[tokio::test]
+    async fn sign_payload_reports_validation_events() {
+        let handler = fake_handler(fixture_device_info());
+        let mut events = handler.events.subscribe();
+
+        let sign = RelayCommand { command: "sign-payload".to_string(), id: Some(1), data: Some(CommandData::Payload { payload: "cGF5bG9hZA==".to_string() }) };
+        assert!(matches!(handler.handle(&sign).await, Some(CommandData::Signature { .. })));
+        assert!(matches!(events.recv().await.unwrap(), RelayEvent::ValidationServed { id: Some(1), .. }));
+
+        let bad = RelayCommand { command: "sign-payload".to_string(), id: Some(2), data: None };
+        assert!(matches!(handler.handle(&bad).await, Some(CommandData::Error { .. })));
+        assert!(matches!(events.recv().await.unwrap(), RelayEvent::ValidationFailed { id: Some(2), .. }));
+    }
+}
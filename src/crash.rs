@@ -0,0 +1,168 @@
+//! Crash reports for panics in supervised resources. A panic hook keeps the location and
+//! backtrace of recent panics, since a caught panic payload carries neither.
+
+use std::{any::Any, backtrace::Backtrace, collections::VecDeque, fs::{self, OpenOptions}, io::{self, Write}, path::PathBuf, sync::{atomic::{AtomicU64, Ordering}, Mutex, Once, PoisonError}, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
+
+// panics are matched back up by message shortly after they're caught
+const MAX_RECENT_PANICS: usize = 16;
+
+static RECENT_PANICS: Mutex<VecDeque<CapturedPanic>> = Mutex::new(VecDeque::new());
+static INSTALL_HOOK: Once = Once::new();
+// orders reports written in the same millisecond
+static REPORT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// What a panic hook saw of a panic
+#[derive(Clone, Debug)]
+pub struct CapturedPanic {
+    pub message: String,
+    pub location: Option<String>,
+    pub thread: Option<String>,
+    pub backtrace: Option<String>,
+}
+
+/// The message of a caught panic; `panic!` gives a `&str` without arguments and a `String` with them
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
+
+/// Start recording backtraces for panics, keeping whatever hook was set before
+pub fn install_panic_hook() {
+    INSTALL_HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let panic = CapturedPanic {
+                message: panic_message(info.payload()),
+                location: info.location().map(|location| location.to_string()),
+                thread: std::thread::current().name().map(str::to_string),
+                backtrace: Some(Backtrace::force_capture().to_string()),
+            };
+            let mut recent = RECENT_PANICS.lock().unwrap_or_else(PoisonError::into_inner);
+            recent.push_back(panic);
+            while recent.len() > MAX_RECENT_PANICS {
+                recent.pop_front();
+            }
+            drop(recent);
+            previous(info);
+        }));
+    });
+}
+
+/// The hook's record of the latest panic with this message, or just the message if the hook missed it
+pub fn take_panic(message: &str) -> CapturedPanic {
+    let mut recent = RECENT_PANICS.lock().unwrap_or_else(PoisonError::into_inner);
+    match recent.iter().rposition(|panic| panic.message == message) {
+        Some(index) => recent.remove(index).unwrap(),
+        None => CapturedPanic { message: message.to_string(), location: None, thread: None, backtrace: None },
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CrashReportConfig {
+    #[serde(default = "CrashReportConfig::default_dir")]
+    pub dir: PathBuf,
+    /// Older reports are deleted past this many
+    #[serde(default = "CrashReportConfig::default_keep")]
+    pub keep: usize,
+}
+
+impl Default for CrashReportConfig {
+    fn default() -> Self {
+        CrashReportConfig {
+            dir: CrashReportConfig::default_dir(),
+            keep: CrashReportConfig::default_keep(),
+        }
+    }
+}
+
+impl CrashReportConfig {
+    fn default_dir() -> PathBuf {
+        PathBuf::from("crashes")
+    }
+
+    fn default_keep() -> usize {
+        20
+    }
+
+    /// Write a report for `panic`, then drop the oldest reports past `keep`
+    pub fn write(&self, resource: &str, source: &str, panic: &CapturedPanic, details: &str) -> io::Result<PathBuf> {
+        fs::create_dir_all(&self.dir)?;
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        // two panics in the same millisecond would otherwise overwrite each other's report
+        let (path, mut file) = loop {
+            let sequence = REPORT_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+            let path = self.dir.join(format!("crash-{time}-{sequence:06}-{source}.txt"));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                file => break (path, file?),
+            }
+        };
+
+        let unknown = || "unknown".to_string();
+        let report = format!(
+            "time: {time}\nresource: {resource}\nsource: {source}\nthread: {}\nlocation: {}\nmessage: {}\n\n{details}\n\nbacktrace:\n{}\n",
+            panic.thread.clone().unwrap_or_else(unknown),
+            panic.location.clone().unwrap_or_else(unknown),
+            panic.message,
+            panic.backtrace.as_deref().unwrap_or("not captured"),
+        );
+        file.write_all(report.as_bytes())?;
+
+        // names sort by time, then by the order they were written in
+        let mut reports: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with("crash-")))
+            .collect();
+        reports.sort();
+        let excess = reports.len().saturating_sub(self.keep.max(1));
+        for old in &reports[..excess] {
+            if let Err(err) = fs::remove_file(old) {
+                println!("Failed to remove old crash report {}: {err}", old.display());
+            }
+        }
+
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::testing::TempDir;
+
+    use super::{take_panic, CrashReportConfig};
+
+    #[test]
+    fn reports_written_together_are_all_kept() {
+        let dir = TempDir::new("crash-reports");
+        let config = CrashReportConfig { dir: dir.path().to_path_buf(), keep: 10 };
+        let panic = take_panic("boom");
+
+        let paths: HashSet<_> = (0..5).map(|_| config.write("Test", "task", &panic, "").unwrap()).collect();
+        assert_eq!(paths.len(), 5);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 5);
+        for path in &paths {
+            assert!(std::fs::read_to_string(path).unwrap().contains("message: boom"));
+        }
+    }
+
+    #[test]
+    fn old_reports_are_rotated_out() {
+        let dir = TempDir::new("crash-rotation");
+        let config = CrashReportConfig { dir: dir.path().to_path_buf(), keep: 2 };
+        let panic = take_panic("boom");
+
+        let paths: Vec<_> = (0..4).map(|_| config.write("Test", "task", &panic, "").unwrap()).collect();
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+        assert!(paths[3].exists());
+    }
+}
@@ -0,0 +1,215 @@
+use std::{path::{Path, PathBuf}, sync::Arc};
+
+use nix::sys::utsname::uname;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "ios")]
+use crate::c::mg_copy_answer_rs;
+use crate::{error::RelayError, protocol::RelayVersions};
+
+/// Where `get-version-info` answers come from
+pub trait DeviceInfoProvider: Send + Sync {
+    fn versions(&self) -> Result<RelayVersions, RelayError>;
+}
+
+/// Reads the running device through uname and MobileGestalt
+#[cfg(feature = "ios")]
+pub struct GestaltDeviceInfo;
+
+#[cfg(feature = "ios")]
+impl DeviceInfoProvider for GestaltDeviceInfo {
+    fn versions(&self) -> Result<RelayVersions, RelayError> {
+        let uts = uname().unwrap();
+        Ok(RelayVersions {
+            hardware_version: uts.machine().to_str().unwrap().to_string(),
+            software_name: "iPhone OS".to_string(),
+            software_version: mg_copy_answer_rs("ProductVersion")?.to_string(),
+            software_build_id: mg_copy_answer_rs("BuildVersion")?.to_string(),
+            unique_device_id: mg_copy_answer_rs("UniqueDeviceID")?.to_string(),
+            serial_number: mg_copy_answer_rs("SerialNumber")?.to_string(),
+        })
+    }
+}
+
+/// Describes the host kernel through uname; identifiers are left empty
+#[cfg(feature = "host")]
+pub struct HostDeviceInfo;
+
+#[cfg(feature = "host")]
+impl DeviceInfoProvider for HostDeviceInfo {
+    fn versions(&self) -> Result<RelayVersions, RelayError> {
+        let uts = uname().unwrap();
+        Ok(RelayVersions {
+            hardware_version: uts.machine().to_string_lossy().to_string(),
+            software_name: uts.sysname().to_string_lossy().to_string(),
+            software_version: uts.release().to_string_lossy().to_string(),
+            software_build_id: uts.version().to_string_lossy().to_string(),
+            unique_device_id: String::new(),
+            serial_number: String::new(),
+        })
+    }
+}
+
+/// Fixed answers loaded from a JSON file shaped like `RelayVersions`, for tests and hosts without MobileGestalt
+pub struct FixtureDeviceInfo {
+    versions: RelayVersions,
+}
+
+impl FixtureDeviceInfo {
+    pub fn new(versions: RelayVersions) -> FixtureDeviceInfo {
+        FixtureDeviceInfo { versions }
+    }
+
+    pub fn load(path: &Path) -> Result<FixtureDeviceInfo, RelayError> {
+        let file = std::fs::read_to_string(path)?;
+        Ok(FixtureDeviceInfo::new(serde_json::from_str(&file)?))
+    }
+}
+
+impl DeviceInfoProvider for FixtureDeviceInfo {
+    fn versions(&self) -> Result<RelayVersions, RelayError> {
+        Ok(self.versions.clone())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum FieldOverride {
+    /// Always report this value
+    Pin(String),
+    /// Don't disclose the real value; reported as an empty string
+    Mask,
+}
+
+impl FieldOverride {
+    fn apply(&self, field: &mut String) {
+        match self {
+            FieldOverride::Pin(value) => *field = value.clone(),
+            FieldOverride::Mask => field.clear(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+pub struct DeviceInfoOverrides {
+    pub hardware_version: Option<FieldOverride>,
+    pub software_name: Option<FieldOverride>,
+    pub software_version: Option<FieldOverride>,
+    pub software_build_id: Option<FieldOverride>,
+    pub unique_device_id: Option<FieldOverride>,
+    pub serial_number: Option<FieldOverride>,
+}
+
+/// Pins or masks individual fields reported by another provider
+pub struct OverrideDeviceInfo {
+    inner: Arc<dyn DeviceInfoProvider>,
+    overrides: DeviceInfoOverrides,
+}
+
+impl OverrideDeviceInfo {
+    pub fn new(inner: Arc<dyn DeviceInfoProvider>, overrides: DeviceInfoOverrides) -> OverrideDeviceInfo {
+        OverrideDeviceInfo { inner, overrides }
+    }
+}
+
+impl DeviceInfoProvider for OverrideDeviceInfo {
+    fn versions(&self) -> Result<RelayVersions, RelayError> {
+        let mut versions = self.inner.versions()?;
+        let fields = [
+            (&self.overrides.hardware_version, &mut versions.hardware_version),
+            (&self.overrides.software_name, &mut versions.software_name),
+            (&self.overrides.software_version, &mut versions.software_version),
+            (&self.overrides.software_build_id, &mut versions.software_build_id),
+            (&self.overrides.unique_device_id, &mut versions.unique_device_id),
+            (&self.overrides.serial_number, &mut versions.serial_number),
+        ];
+        for (field_override, field) in fields {
+            if let Some(field_override) = field_override {
+                field_override.apply(field);
+            }
+        }
+        Ok(versions)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct DeviceInfoConfig {
+    /// Answer from this JSON file instead of asking the device
+    pub fixture: Option<PathBuf>,
+    #[serde(default)]
+    pub overrides: DeviceInfoOverrides,
+}
+
+impl DeviceInfoConfig {
+    pub fn build(&self) -> Result<Arc<dyn DeviceInfoProvider>, RelayError> {
+        let base: Arc<dyn DeviceInfoProvider> = match &self.fixture {
+            Some(path) => Arc::new(FixtureDeviceInfo::load(path)?),
+            #[cfg(feature = "ios")]
+            None => Arc::new(GestaltDeviceInfo),
+            #[cfg(all(feature = "host", not(feature = "ios")))]
+            None => Arc::new(HostDeviceInfo),
+            // lib.rs already refuses to build this way
+            #[cfg(not(any(feature = "ios", feature = "host")))]
+            None => unreachable!(),
+        };
+        Ok(Arc::new(OverrideDeviceInfo::new(base, self.overrides.clone())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::{Path, PathBuf}, sync::Arc};
+
+    use crate::error::RelayError;
+
+    use super::{DeviceInfoConfig, DeviceInfoOverrides, DeviceInfoProvider, FieldOverride, FixtureDeviceInfo, OverrideDeviceInfo};
+
+    fn fixture() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/device-info.json")
+    }
+
+    #[test]
+    fn fixture_loads() {
+        let versions = FixtureDeviceInfo::load(&fixture()).unwrap().versions().unwrap();
+        assert_eq!(versions.hardware_version, "iPhone10,1");
+        assert_eq!(versions.software_version, "16.7.8");
+        assert_eq!(versions.software_build_id, "20H343");
+    }
+
+    #[test]
+    fn missing_fixture_is_an_error() {
+        let missing = FixtureDeviceInfo::load(Path::new("fixtures/does-not-exist.json"));
+        assert!(matches!(missing, Err(RelayError::IoError(_))));
+    }
+
+    #[test]
+    fn overrides_pin_and_mask_fields() {
+        let overrides = DeviceInfoOverrides {
+            software_version: Some(FieldOverride::Pin("17.0".to_string())),
+            serial_number: Some(FieldOverride::Mask),
+            unique_device_id: Some(FieldOverride::Mask),
+            ..Default::default()
+        };
+        let device_info = OverrideDeviceInfo::new(Arc::new(FixtureDeviceInfo::load(&fixture()).unwrap()), overrides);
+
+        let versions = device_info.versions().unwrap();
+        assert_eq!(versions.software_version, "17.0");
+        assert_eq!(versions.serial_number, "");
+        assert_eq!(versions.unique_device_id, "");
+        // untouched fields come straight from the fixture
+        assert_eq!(versions.software_build_id, "20H343");
+    }
+
+    #[test]
+    fn config_builds_fixture_with_overrides() {
+        let config: DeviceInfoConfig = serde_json::from_value(serde_json::json!({
+            "fixture": fixture(),
+            "overrides": { "hardware_version": { "pin": "iPhone12,8" }, "serial_number": "mask" },
+        })).unwrap();
+
+        let versions = config.build().unwrap().versions().unwrap();
+        assert_eq!(versions.hardware_version, "iPhone12,8");
+        assert_eq!(versions.serial_number, "");
+        assert_eq!(versions.software_version, "16.7.8");
+    }
+}
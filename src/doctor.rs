@@ -0,0 +1,432 @@
+//! `relayserver doctor`: checks each thing a device needs to serve validation requests on its own,
+//! so a broken entitlement, a dead absd and a firewalled relay don't all look like "validation failed".
+
+use std::{future::Future, os::unix::fs::PermissionsExt, path::{Path, PathBuf}, time::Duration};
+
+use serde::Serialize;
+use tokio::time::Instant;
+use tokio_tungstenite::connect_async;
+
+use crate::{device::DeviceInfoConfig, error::{NacErrorCode, RelayError}, nac::{initialize_validation, validation_cert, NacConfig}};
+
+const LAUNCH_DAEMON: &str = "/Library/LaunchDaemons/dev.copper.relayserver.plist";
+// what the binary is expected to be signed with
+const ENTITLEMENTS: &str = include_str!("../Entitlements.xml");
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+const RELAY_HINT: &str = "check the relay url in config.json and that this device can reach it";
+const GESTALT_HINT: &str = "protected MobileGestalt keys need com.apple.private.MobileGestalt.AllowedProtectedKeys; re-sign with `ldid -SEntitlements.xml`";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CheckStatus {
+    Pass,
+    /// Works, but probably not how it should be set up
+    Warn,
+    Fail,
+    /// Not applicable here, or blocked by an earlier failure
+    Skip,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DoctorCheck {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+    pub hint: Option<String>,
+    pub duration_ms: u64,
+}
+
+impl DoctorCheck {
+    fn new(name: &str, status: CheckStatus, detail: impl Into<String>) -> DoctorCheck {
+        DoctorCheck { name: name.to_string(), status, detail: detail.into(), hint: None, duration_ms: 0 }
+    }
+
+    fn pass(name: &str, detail: impl Into<String>) -> DoctorCheck {
+        DoctorCheck::new(name, CheckStatus::Pass, detail)
+    }
+
+    fn skip(name: &str, detail: impl Into<String>) -> DoctorCheck {
+        DoctorCheck::new(name, CheckStatus::Skip, detail)
+    }
+
+    fn fail(name: &str, err: &RelayError) -> DoctorCheck {
+        DoctorCheck::new(name, CheckStatus::Fail, err.to_string()).hint(hint_for(err))
+    }
+
+    fn hint(mut self, hint: impl Into<String>) -> DoctorCheck {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    fn took(mut self, started: Instant) -> DoctorCheck {
+        self.duration_ms = started.elapsed().as_millis() as u64;
+        self
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DoctorReport {
+    pub passed: bool,
+    pub checks: Vec<DoctorCheck>,
+}
+
+impl DoctorReport {
+    pub fn print(&self) {
+        for check in &self.checks {
+            let status = match check.status {
+                CheckStatus::Pass => "PASS",
+                CheckStatus::Warn => "WARN",
+                CheckStatus::Fail => "FAIL",
+                CheckStatus::Skip => "SKIP",
+            };
+            println!("{status}  {:<22} {} ({}ms)", check.name, check.detail, check.duration_ms);
+            if let Some(hint) = &check.hint {
+                println!("      {:<22} hint: {hint}", "");
+            }
+        }
+        let failed = self.checks.iter().filter(|check| check.status == CheckStatus::Fail).count();
+        if failed == 0 {
+            println!("All checks passed");
+        } else {
+            println!("{failed} of {} checks failed", self.checks.len());
+        }
+    }
+}
+
+/// What the daemon would run with
+pub struct DoctorOptions {
+    pub config_path: PathBuf,
+    /// Why the config file didn't load, if it exists and didn't
+    pub config_error: Option<String>,
+    pub relay_url: String,
+    pub nac: NacConfig,
+    pub device_info: DeviceInfoConfig,
+}
+
+pub async fn run_doctor(options: &DoctorOptions) -> DoctorReport {
+    let mut checks = vec![
+        check_config(&options.config_path, options.config_error.as_deref()),
+        check_entitlements(),
+        check_launch_daemon(),
+        check_gestalt(),
+        check_device_info(&options.device_info),
+        check_relay(&options.relay_url).await,
+    ];
+    checks.extend(check_nac(&options.nac).await);
+    DoctorReport {
+        passed: checks.iter().all(|check| check.status != CheckStatus::Fail),
+        checks,
+    }
+}
+
+fn hint_for(err: &RelayError) -> String {
+    if let Some(code) = err.nac_code() {
+        return match code {
+            _ if code.is_port_death() => "absd went away mid-request; run doctor again, and reboot if it keeps happening".to_string(),
+            NacErrorCode::NoAccess | NacErrorCode::BootstrapNotPrivileged =>
+                "the binary is missing the abs-client entitlement; re-sign it with `ldid -SEntitlements.xml`".to_string(),
+            NacErrorCode::BootstrapUnknownService => "absd isn't registered; check `launchctl list | grep absd`".to_string(),
+            NacErrorCode::NotSupported => "this device doesn't support NAC".to_string(),
+            NacErrorCode::MigBadId | NacErrorCode::MigBadArguments => "absd's interface differs on this iOS version".to_string(),
+            _ if code.is_transient() => "absd is busy or slow; run doctor again".to_string(),
+            _ => code.description().to_string(),
+        }
+    }
+    match err {
+        RelayError::NacAgentUnavailable(_) => "start `relayserver nac-agent` on the agent device and check the nac url in config.json".to_string(),
+        RelayError::NacAgentError(_) => "check the nac token in config.json matches the agent's".to_string(),
+        RelayError::RequestError(_) => "check this device can reach static.ess.apple.com and identity.ess.apple.com".to_string(),
+        RelayError::WSError(_) => RELAY_HINT.to_string(),
+        RelayError::GestaltError(_, _) => GESTALT_HINT.to_string(),
+        RelayError::IoError(_) => "check the file exists and is readable by the daemon's user".to_string(),
+        _ => "see the error above".to_string(),
+    }
+}
+
+async fn timed<T>(future: impl Future<Output = T>) -> (T, Instant) {
+    let started = Instant::now();
+    (future.await, started)
+}
+
+fn check_config(path: &Path, parse_error: Option<&str>) -> DoctorCheck {
+    let name = "config-file";
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return DoctorCheck::new(name, CheckStatus::Warn, format!("no {} in {}; defaults are used", path.display(), std::env::current_dir().unwrap_or_default().display()))
+                .hint("run from the LaunchDaemon's WorkingDirectory (/var/mobile/); the file is written on first registration")
+        },
+        Err(err) => return DoctorCheck::fail(name, &err.into()),
+    };
+    if let Some(parse_error) = parse_error {
+        return DoctorCheck::new(name, CheckStatus::Fail, format!("{} doesn't load: {parse_error}", path.display()))
+            .hint("fix the file; the daemon won't start until it loads")
+    }
+    // the registration is saved back into it
+    if let Err(err) = std::fs::OpenOptions::new().append(true).open(path) {
+        return DoctorCheck::new(name, CheckStatus::Fail, format!("{} isn't writable: {err}", path.display()))
+            .hint("give the daemon's user (mobile) write access, or the relay code changes on every restart")
+    }
+    if metadata.permissions().mode() & 0o044 != 0 {
+        return DoctorCheck::new(name, CheckStatus::Warn, format!("{} is readable by other users", path.display()))
+            .hint(format!("it holds the relay secret and API tokens; `chmod 600 {}`", path.display()))
+    }
+    DoctorCheck::pass(name, format!("{} parses and is writable", path.display()))
+}
+
+fn check_entitlements() -> DoctorCheck {
+    let name = "entitlements";
+    if !cfg!(feature = "ios") {
+        return DoctorCheck::skip(name, "only needed for iOS builds")
+    }
+    let expected: plist::Dictionary = plist::from_bytes(ENTITLEMENTS.as_bytes()).expect("Entitlements.xml is a dictionary plist");
+    let binary = match std::env::current_exe().and_then(std::fs::read) {
+        Ok(binary) => binary,
+        Err(err) => return DoctorCheck::fail(name, &err.into()),
+    };
+    let Some(signed) = embedded_entitlements(&binary) else {
+        return DoctorCheck::new(name, CheckStatus::Fail, "the binary has no embedded entitlements")
+            .hint("sign it with `ldid -SEntitlements.xml`")
+    };
+    let missing = missing_entitlements(&expected, &signed);
+    if !missing.is_empty() {
+        return DoctorCheck::new(name, CheckStatus::Fail, format!("missing or wrong: {}", missing.join(", ")))
+            .hint("re-sign with the repo's Entitlements.xml: `ldid -SEntitlements.xml`")
+    }
+    DoctorCheck::pass(name, "the binary is signed with everything in Entitlements.xml")
+}
+
+// ldid embeds the entitlements as an XML plist in the code signature
+fn embedded_entitlements(binary: &[u8]) -> Option<plist::Dictionary> {
+    let mut rest = binary;
+    while let Some(start) = find(rest, b"<?xml") {
+        let candidate = &rest[start..];
+        let end = find(candidate, b"</plist>")? + b"</plist>".len();
+        if let Ok(dict) = plist::from_bytes::<plist::Dictionary>(&candidate[..end]) {
+            if dict.contains_key("abs-client") || dict.contains_key("platform-application") {
+                return Some(dict)
+            }
+        }
+        rest = &candidate[end..];
+    }
+    None
+}
+
+fn missing_entitlements(expected: &plist::Dictionary, signed: &plist::Dictionary) -> Vec<String> {
+    expected.iter()
+        .filter(|(key, value)| !signed.get(key).is_some_and(|signed| entitlement_covers(signed, value)))
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// lists (like AllowedProtectedKeys) only need to include what's expected
+fn entitlement_covers(signed: &plist::Value, expected: &plist::Value) -> bool {
+    match (signed, expected) {
+        (plist::Value::Array(signed), plist::Value::Array(expected)) => expected.iter().all(|item| signed.contains(item)),
+        (signed, expected) => signed == expected,
+    }
+}
+
+fn check_launch_daemon() -> DoctorCheck {
+    let name = "launch-daemon";
+    if !cfg!(feature = "ios") {
+        return DoctorCheck::skip(name, "only used on iOS")
+    }
+    let daemon: plist::Dictionary = match plist::from_file(LAUNCH_DAEMON) {
+        Ok(daemon) => daemon,
+        Err(err) => return DoctorCheck::new(name, CheckStatus::Fail, format!("can't read {LAUNCH_DAEMON}: {err}"))
+            .hint("install the relay-package .deb"),
+    };
+    let program = daemon.get("Program").and_then(|program| program.as_string()).unwrap_or_default();
+    if let Ok(exe) = std::env::current_exe() {
+        if Path::new(program) != exe {
+            return DoctorCheck::new(name, CheckStatus::Warn, format!("the daemon runs {program}, but this is {}", exe.display()))
+                .hint("run doctor as the installed binary, or reinstall the package")
+        }
+    }
+    if daemon.get("KeepAlive").and_then(|keep_alive| keep_alive.as_boolean()) != Some(true) {
+        return DoctorCheck::new(name, CheckStatus::Warn, "KeepAlive is off, so the daemon won't be restarted if it exits")
+            .hint(format!("set KeepAlive and `launchctl unload {LAUNCH_DAEMON} && launchctl load {LAUNCH_DAEMON}`"))
+    }
+    let working_directory = daemon.get("WorkingDirectory").and_then(|dir| dir.as_string()).unwrap_or("/");
+    DoctorCheck::pass(name, format!("runs {program} from {working_directory}"))
+}
+
+fn check_gestalt() -> DoctorCheck {
+    let name = "mobilegestalt";
+    #[cfg(feature = "ios")]
+    {
+        let started = Instant::now();
+        let failed: Vec<String> = ["ProductVersion", "BuildVersion", "UniqueDeviceID", "SerialNumber"].into_iter()
+            .filter_map(|key| crate::c::mg_copy_answer_rs(key).err())
+            .map(|err| err.to_string())
+            .collect();
+        if !failed.is_empty() {
+            return DoctorCheck::new(name, CheckStatus::Fail, failed.join("; "))
+                .hint(GESTALT_HINT)
+                .took(started)
+        }
+        DoctorCheck::pass(name, "read the version and protected identifier keys").took(started)
+    }
+    #[cfg(not(feature = "ios"))]
+    DoctorCheck::skip(name, "not available on host builds")
+}
+
+fn check_device_info(config: &DeviceInfoConfig) -> DoctorCheck {
+    let name = "device-info";
+    let started = Instant::now();
+    match config.build().and_then(|device_info| device_info.versions()) {
+        Ok(versions) => DoctorCheck::pass(name, format!("{} {} ({}) on {}", versions.software_name, versions.software_version, versions.software_build_id, versions.hardware_version)),
+        Err(err) => DoctorCheck::fail(name, &err),
+    }.took(started)
+}
+
+async fn check_relay(url: &str) -> DoctorCheck {
+    let name = "relay";
+    let (connected, started) = timed(tokio::time::timeout(RELAY_TIMEOUT, connect_async(url))).await;
+    match connected {
+        Ok(Ok((mut socket, _))) => {
+            let _ = socket.close(None).await;
+            DoctorCheck::pass(name, format!("connected to {url}"))
+        },
+        Ok(Err(err)) => DoctorCheck::fail(name, &err.into()),
+        Err(_) => DoctorCheck::new(name, CheckStatus::Fail, format!("no answer from {url} in {RELAY_TIMEOUT:?}"))
+            .hint(RELAY_HINT),
+    }.took(started)
+}
+
+// each phase on its own, so a slow or failing one stands out
+async fn check_nac(config: &NacConfig) -> Vec<DoctorCheck> {
+    let backend = config.build();
+    let mut checks = vec![];
+
+    let (cert, started) = timed(validation_cert()).await;
+    let cert = match cert {
+        Ok(cert) => {
+            checks.push(DoctorCheck::pass("apple-cert", "fetched Apple's validation cert").took(started));
+            cert
+        },
+        Err(err) => {
+            checks.push(DoctorCheck::fail("apple-cert", &err).took(started));
+            checks.extend(["nac-init", "apple-session", "nac-key-establishment", "nac-sign"].map(|name| DoctorCheck::skip(name, "needs apple-cert")));
+            return checks
+        },
+    };
+
+    let (init, started) = timed(backend.init(cert)).await;
+    let (ctx, session_request) = match init {
+        Ok(init) => {
+            checks.push(DoctorCheck::pass("nac-init", "the backend started a session").took(started));
+            init
+        },
+        Err(err) => {
+            checks.push(DoctorCheck::fail("nac-init", &err).took(started));
+            checks.extend(["apple-session", "nac-key-establishment", "nac-sign"].map(|name| DoctorCheck::skip(name, "needs nac-init")));
+            return checks
+        },
+    };
+
+    if backend.is_fake() {
+        checks.extend(["apple-session", "nac-key-establishment"].map(|name| DoctorCheck::skip(name, "the fake backend has no session to establish")));
+    } else {
+        let (session, started) = timed(initialize_validation(session_request)).await;
+        let session = match session {
+            Ok(session) => {
+                checks.push(DoctorCheck::pass("apple-session", "Apple answered the session request").took(started));
+                session
+            },
+            Err(err) => {
+                checks.push(DoctorCheck::fail("apple-session", &err).took(started));
+                checks.extend(["nac-key-establishment", "nac-sign"].map(|name| DoctorCheck::skip(name, "needs apple-session")));
+                return checks
+            },
+        };
+
+        let (established, started) = timed(backend.key_establishment(ctx, &session)).await;
+        if let Err(err) = established {
+            checks.push(DoctorCheck::fail("nac-key-establishment", &err).took(started));
+            checks.push(DoctorCheck::skip("nac-sign", "needs nac-key-establishment"));
+            return checks
+        }
+        checks.push(DoctorCheck::pass("nac-key-establishment", "the backend accepted Apple's session info").took(started));
+    }
+
+    let (signature, started) = timed(backend.sign(ctx, &[])).await;
+    checks.push(match signature {
+        Ok(signature) => DoctorCheck::pass("nac-sign", format!("generated {} bytes of validation data", signature.len())),
+        Err(err) => DoctorCheck::fail("nac-sign", &err),
+    }.took(started));
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::{error::{NacErrorCode, RelayError}, testing::TempDir};
+
+    use super::{check_config, embedded_entitlements, entitlement_covers, hint_for, missing_entitlements, CheckStatus, ENTITLEMENTS};
+
+    fn expected() -> plist::Dictionary {
+        plist::from_bytes(ENTITLEMENTS.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn finds_the_entitlements_among_other_plists() {
+        let mut binary = b"\xcf\xfa\xed\xfe junk".to_vec();
+        binary.extend_from_slice(b"<?xml version=\"1.0\"?><plist version=\"1.0\"><dict><key>CFBundleName</key><string>x</string></dict></plist>");
+        binary.extend_from_slice(b"\0\0\0");
+        binary.extend_from_slice(ENTITLEMENTS.as_bytes());
+        binary.extend_from_slice(b"\0 more junk");
+
+        assert_eq!(embedded_entitlements(&binary), Some(expected()));
+        assert_eq!(embedded_entitlements(b"no signature here"), None);
+    }
+
+    #[test]
+    fn arrays_only_need_what_is_expected() {
+        let signed = plist::Value::Array(["SerialNumber", "UniqueDeviceID", "WifiAddress"].map(plist::Value::from).to_vec());
+        let expected = plist::Value::Array(["SerialNumber", "UniqueDeviceID"].map(plist::Value::from).to_vec());
+        assert!(entitlement_covers(&signed, &expected));
+        assert!(!entitlement_covers(&expected, &signed));
+        assert!(entitlement_covers(&plist::Value::Boolean(true), &plist::Value::Boolean(true)));
+        assert!(!entitlement_covers(&plist::Value::Integer(1.into()), &plist::Value::Integer(772496756.into())));
+    }
+
+    #[test]
+    fn reports_missing_and_wrong_entitlements() {
+        let mut signed = expected();
+        assert!(missing_entitlements(&expected(), &signed).is_empty());
+
+        signed.remove("abs-client");
+        signed.insert("com.apple.private.MobileGestalt.AllowedProtectedKeys".to_string(), plist::Value::Array(vec!["SerialNumber".into()]));
+        assert_eq!(missing_entitlements(&expected(), &signed), ["com.apple.private.MobileGestalt.AllowedProtectedKeys", "abs-client"]);
+    }
+
+    #[test]
+    fn hints_point_at_the_likely_fix() {
+        assert!(hint_for(&RelayError::NacError(NacErrorCode::NoAccess)).contains("abs-client"));
+        assert!(hint_for(&RelayError::NacError(NacErrorCode::SendInvalidDest)).contains("absd went away"));
+        assert!(hint_for(&RelayError::NacAgentUnavailable("refused".to_string())).contains("nac-agent"));
+        assert_eq!(hint_for(&RelayError::BadCommand("nope".to_string())), "see the error above");
+    }
+
+    #[test]
+    fn config_file_permissions_and_parsing() {
+        let dir = TempDir::new("doctor");
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, "{}").unwrap();
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(check_config(&path, None).status, CheckStatus::Pass);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(check_config(&path, None).status, CheckStatus::Warn);
+        assert_eq!(check_config(&path, Some("expected value at line 1 column 1")).status, CheckStatus::Fail);
+        assert_eq!(check_config(&dir.path().join("missing.json"), None).status, CheckStatus::Warn);
+    }
+}
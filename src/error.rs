@@ -1,7 +1,9 @@
-use std::sync::Arc;
+use std::{fmt::Display, sync::Arc};
 
 use thiserror::Error;
 
+use crate::util::{ManagerError, ResourceError};
+
 
 
 #[derive(Error, Debug)]
@@ -11,18 +13,257 @@ pub enum RelayError {
     #[error("HTTP error: {0}")]
     RequestError(#[from] reqwest::Error),
     #[error("NAC error: {0}")]
-    NacError(u64),
+    NacError(NacErrorCode),
     #[error("Resource Timeout")]
     ResourceTimeout,
+    #[error("Resource task exited")]
+    ResourceExited,
+    #[error("Resource manager shut down")]
+    ResourceShutdown,
     #[error("Resource Failure")]
     ResourceFailure(#[from] Arc<RelayError>),
     #[error("Resource Panic {0}")]
     ResourcePanic(String),
+    #[error("Resource unhealthy: {0}")]
+    ResourceUnhealthy(Arc<RelayError>),
     #[error("Do not retry {0}")]
     DoNotRetry(Box<RelayError>),
+    // boxed: tungstenite's error is several times the size of everything else here
     #[error("WS error: {0}")]
-    WSError(#[from] tokio_tungstenite::tungstenite::Error),
+    WSError(Box<tokio_tungstenite::tungstenite::Error>),
     #[error("JSON error: {0}")]
     JSONError(#[from] serde_json::Error),
+    #[error("NAC agent unavailable: {0}")]
+    NacAgentUnavailable(String),
+    #[error("NAC agent error: {0}")]
+    NacAgentError(String),
+    #[error("MobileGestalt error for {0}: {1}")]
+    GestaltError(String, String),
+    #[error("Bad command: {0}")]
+    BadCommand(String),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Base64 error: {0}")]
+    Base64Error(#[from] base64::DecodeError),
+    #[error("Hook failed: {0}")]
+    HookFailed(String),
+    #[error("QR error: {0}")]
+    QrError(#[from] qrcode::types::QrError),
+    #[error("PNG error: {0}")]
+    PngError(#[from] png::EncodingError),
+    #[error("Supervisor error: {0}")]
+    SupervisorError(#[from] crate::supervisor::SupervisorError),
+    #[error("Self-test failed: {0}")]
+    SelfTestFailed(String),
+    #[error("No pong from relay in {0:?}")]
+    RelayUnresponsive(std::time::Duration),
+}
+
+impl RelayError {
+    /// The NAC code behind this error, looking through resource wrappers
+    pub fn nac_code(&self) -> Option<NacErrorCode> {
+        match self {
+            RelayError::NacError(code) => Some(*code),
+            RelayError::ResourceFailure(inner) | RelayError::ResourceUnhealthy(inner) => inner.nac_code(),
+            RelayError::DoNotRetry(inner) => inner.nac_code(),
+            _ => None,
+        }
+    }
+
+    /// The backend's connection (absd port or agent socket) went away mid-operation
+    pub fn is_backend_lost(&self) -> bool {
+        matches!(self, RelayError::NacAgentUnavailable(_)) || self.nac_code().is_some_and(|code| code.is_port_death())
+    }
+
+    // anything that isn't a known-permanent NAC failure is worth another attempt
+    pub fn is_transient(&self) -> bool {
+        self.nac_code().map(|code| code.is_transient()).unwrap_or(true)
+    }
+
+    pub fn is_permanent(&self) -> bool {
+        !self.is_transient()
+    }
+}
+
+impl ResourceError for RelayError {
+    // permanent NAC failures (missing entitlement, unsupported device) won't fix themselves
+    fn is_final(&self) -> bool {
+        matches!(self, RelayError::DoNotRetry(_)) || self.is_permanent()
+    }
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for RelayError {
+    fn from(err: tokio_tungstenite::tungstenite::Error) -> Self {
+        RelayError::WSError(Box::new(err))
+    }
+}
+
+impl From<ManagerError<RelayError>> for RelayError {
+    fn from(err: ManagerError<RelayError>) -> Self {
+        match err {
+            ManagerError::Failed(err) => RelayError::ResourceFailure(err),
+            ManagerError::Panic(message) => RelayError::ResourcePanic(message),
+            ManagerError::Unhealthy(err) => RelayError::ResourceUnhealthy(err),
+            ManagerError::Timeout => RelayError::ResourceTimeout,
+            ManagerError::Exited => RelayError::ResourceExited,
+            ManagerError::Shutdown => RelayError::ResourceShutdown,
+        }
+    }
+}
+
+/// Return codes seen from absd, either mach/MIG transport failures or absd's own NAC codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NacErrorCode {
+    // kern_return_t
+    InvalidArgument,
+    Failure,
+    ResourceShortage,
+    NoAccess,
+    Aborted,
+    OperationTimedOut,
+    NotSupported,
+    Denied,
+    // bootstrap_look_up
+    BootstrapNotPrivileged,
+    BootstrapUnknownService,
+    // mach_msg
+    SendInvalidDest,
+    SendTimedOut,
+    SendInterrupted,
+    SendInvalidRight,
+    RcvTimedOut,
+    RcvInterrupted,
+    RcvPortChanged,
+    RcvPortDied,
+    // MIG
+    MigTypeError,
+    MigReplyMismatch,
+    MigBadId,
+    MigBadArguments,
+    MigNoReply,
+    MigServerDied,
+    // absd's NAC routines
+    NacBadCert,
+    NacBadSessionInfo,
+    NacBadContext,
+    NacUnsupportedHardware,
+    NacNotActivated,
+    // absd returned a code we don't have a name for
+    Unknown(i32),
 }
 
+impl NacErrorCode {
+    const TABLE: &'static [(i32, NacErrorCode, &'static str)] = &[
+        (4, NacErrorCode::InvalidArgument, "KERN_INVALID_ARGUMENT: absd rejected the arguments"),
+        (5, NacErrorCode::Failure, "KERN_FAILURE: absd reported a generic failure"),
+        (6, NacErrorCode::ResourceShortage, "KERN_RESOURCE_SHORTAGE: absd is out of resources"),
+        (8, NacErrorCode::NoAccess, "KERN_NO_ACCESS: missing abs-client entitlement"),
+        (14, NacErrorCode::Aborted, "KERN_ABORTED: the operation was aborted"),
+        (46, NacErrorCode::NotSupported, "KERN_NOT_SUPPORTED: the device does not support NAC"),
+        (49, NacErrorCode::OperationTimedOut, "KERN_OPERATION_TIMED_OUT: absd did not answer in time"),
+        (53, NacErrorCode::Denied, "KERN_DENIED: absd denied the request"),
+        (1100, NacErrorCode::BootstrapNotPrivileged, "BOOTSTRAP_NOT_PRIVILEGED: not allowed to look up com.apple.absd"),
+        (1102, NacErrorCode::BootstrapUnknownService, "BOOTSTRAP_UNKNOWN_SERVICE: com.apple.absd is not registered on this device"),
+        (0x10000003, NacErrorCode::SendInvalidDest, "MACH_SEND_INVALID_DEST: the absd port is dead"),
+        (0x10000004, NacErrorCode::SendTimedOut, "MACH_SEND_TIMED_OUT: absd is busy"),
+        (0x10000007, NacErrorCode::SendInterrupted, "MACH_SEND_INTERRUPTED: the send was interrupted"),
+        (0x1000000a, NacErrorCode::SendInvalidRight, "MACH_SEND_INVALID_RIGHT: the absd port right is no longer valid"),
+        (0x10004003, NacErrorCode::RcvTimedOut, "MACH_RCV_TIMED_OUT: absd did not reply in time"),
+        (0x10004005, NacErrorCode::RcvInterrupted, "MACH_RCV_INTERRUPTED: the receive was interrupted"),
+        (0x10004006, NacErrorCode::RcvPortChanged, "MACH_RCV_PORT_CHANGED: the reply port changed"),
+        (0x10004009, NacErrorCode::RcvPortDied, "MACH_RCV_PORT_DIED: absd died while we waited for a reply"),
+        (-300, NacErrorCode::MigTypeError, "MIG_TYPE_ERROR: absd replied with unexpected types"),
+        (-301, NacErrorCode::MigReplyMismatch, "MIG_REPLY_MISMATCH: absd replied to a different request"),
+        (-303, NacErrorCode::MigBadId, "MIG_BAD_ID: absd does not implement this routine on this iOS version"),
+        (-304, NacErrorCode::MigBadArguments, "MIG_BAD_ARGUMENTS: absd rejected the message layout"),
+        (-305, NacErrorCode::MigNoReply, "MIG_NO_REPLY: absd did not send a reply"),
+        (-308, NacErrorCode::MigServerDied, "MIG_SERVER_DIED: absd died while handling the request"),
+        (-44020, NacErrorCode::NacBadCert, "NAC rejected Apple's validation cert; it is fetched again on restart"),
+        (-44021, NacErrorCode::NacBadSessionInfo, "NAC rejected Apple's session info; a new session is needed"),
+        (-44022, NacErrorCode::NacBadContext, "NAC does not know this validation context; it expired or absd restarted"),
+        (-44023, NacErrorCode::NacUnsupportedHardware, "NAC could not read this device's hardware identifiers"),
+        (-44030, NacErrorCode::NacNotActivated, "NAC refuses to sign on a device that is not activated"),
+    ];
+
+    pub fn from_code(code: i32) -> NacErrorCode {
+        Self::TABLE.iter()
+            .find(|(raw, _, _)| *raw == code)
+            .map(|(_, named, _)| *named)
+            .unwrap_or(NacErrorCode::Unknown(code))
+    }
+
+    pub fn code(&self) -> i32 {
+        match self {
+            NacErrorCode::Unknown(code) => *code,
+            known => Self::TABLE.iter().find(|(_, named, _)| named == known).map(|(raw, _, _)| *raw).expect("named code missing from table"),
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            NacErrorCode::Unknown(_) => "unrecognised NAC error",
+            known => Self::TABLE.iter().find(|(_, named, _)| named == known).map(|(_, _, desc)| *desc).expect("named code missing from table"),
+        }
+    }
+
+    /// The cached absd port no longer points at a live absd
+    pub fn is_port_death(&self) -> bool {
+        matches!(self, NacErrorCode::SendInvalidDest | NacErrorCode::SendInvalidRight | NacErrorCode::RcvPortDied | NacErrorCode::MigServerDied)
+    }
+
+    /// Whether retrying later may succeed; permanent codes need someone to fix the device
+    pub fn is_transient(&self) -> bool {
+        !matches!(self,
+            NacErrorCode::NoAccess |
+            NacErrorCode::NotSupported |
+            NacErrorCode::Denied |
+            NacErrorCode::BootstrapNotPrivileged |
+            NacErrorCode::BootstrapUnknownService |
+            NacErrorCode::MigBadId |
+            NacErrorCode::MigBadArguments |
+            NacErrorCode::NacUnsupportedHardware |
+            NacErrorCode::NacNotActivated)
+    }
+}
+
+impl From<i32> for NacErrorCode {
+    fn from(value: i32) -> Self {
+        NacErrorCode::from_code(value)
+    }
+}
+
+impl Display for NacErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}, {})", self.code(), self.description(), if self.is_transient() { "transient" } else { "permanent" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NacErrorCode;
+
+    #[test]
+    fn codes_round_trip_through_the_table() {
+        for (raw, named, _) in NacErrorCode::TABLE {
+            assert_eq!(NacErrorCode::from(*raw), *named);
+            assert_eq!(named.code(), *raw);
+        }
+        assert_eq!(NacErrorCode::from(-44022), NacErrorCode::NacBadContext);
+        assert_eq!(NacErrorCode::from(12345), NacErrorCode::Unknown(12345));
+        assert_eq!(NacErrorCode::Unknown(12345).code(), 12345);
+    }
+
+    #[test]
+    fn codes_are_classified() {
+        assert!(NacErrorCode::RcvPortDied.is_port_death() && NacErrorCode::RcvPortDied.is_transient());
+        assert!(NacErrorCode::MigServerDied.is_port_death());
+        assert!(!NacErrorCode::SendTimedOut.is_port_death() && NacErrorCode::SendTimedOut.is_transient());
+        assert!(NacErrorCode::NacBadSessionInfo.is_transient() && !NacErrorCode::NacBadSessionInfo.is_port_death());
+        for permanent in [NacErrorCode::NoAccess, NacErrorCode::BootstrapUnknownService, NacErrorCode::MigBadId, NacErrorCode::NacUnsupportedHardware, NacErrorCode::NacNotActivated] {
+            assert!(!permanent.is_transient(), "{permanent:?}");
+            assert!(!permanent.is_port_death(), "{permanent:?}");
+        }
+        // nothing we can't name is assumed to be hopeless
+        assert!(NacErrorCode::Unknown(-1).is_transient());
+    }
+}
@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+use serde::{Serialize, Serializer};
+
+fn as_millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_millis() as u64)
+}
+
+/// What a running relay provider reports to its subscribers
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum RelayEvent {
+    /// Opening the websocket to the relay
+    Connecting {
+        url: String,
+    },
+    /// The relay accepted our registration; `is_new` when it handed out a different code
+    Registered {
+        code: String,
+        is_new: bool,
+    },
+    /// The websocket to the relay closed; a reconnect follows unless the provider was stopped
+    Disconnected {
+        reason: String,
+    },
+    ValidationServed {
+        id: Option<u64>,
+        #[serde(serialize_with = "as_millis")]
+        latency: Duration,
+    },
+    ValidationFailed {
+        id: Option<u64>,
+        error: String,
+    },
+    /// The connection keeps dropping soon after registering; reconnecting is held off for `in`
+    Flapping {
+        unstable_runs: u32,
+        #[serde(serialize_with = "as_millis")]
+        r#in: Duration,
+    },
+    /// Connecting failed; the next attempt is in `in`
+    RetryScheduled {
+        error: String,
+        #[serde(serialize_with = "as_millis")]
+        r#in: Duration,
+    },
+}
@@ -0,0 +1,409 @@
+//! Run shell commands or POST webhooks on the LAN when something about the relay connection needs a human:
+//! a new pairing code, the relay being unreachable for a while, failed validation requests.
+
+use std::{net::IpAddr, process::Stdio, sync::Arc, time::Duration};
+
+use backon::{ExponentialBuilder, Retryable};
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::{io::AsyncWriteExt, process::Command, select, sync::broadcast, task::JoinHandle, time::{self, Instant}};
+
+use crate::{error::RelayError, events::RelayEvent};
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookTrigger {
+    /// The relay handed out a different pairing code
+    NewCode,
+    /// The relay has been unreachable for `failure_after_secs`
+    ProlongedFailure,
+    ValidationFailed,
+    /// Reconnected after a prolonged failure
+    Recovered,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum HookAction {
+    /// Run through `sh -c`, with the payload on stdin
+    Command { command: String },
+    /// POST the payload as JSON; only to this machine or the LAN
+    Webhook {
+        #[serde(deserialize_with = "lan_url")]
+        url: String,
+    },
+}
+
+fn lan_url<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let url = String::deserialize(deserializer)?;
+    check_lan_url(&url).map_err(serde::de::Error::custom)?;
+    Ok(url)
+}
+
+/// Webhooks carry pairing codes, so they may only go to loopback, private or link-local addresses,
+/// or to hostnames that only resolve on the LAN
+pub fn check_lan_url(url: &str) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|err| format!("bad webhook url {url}: {err}"))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("webhook url {url} isn't http or https"))
+    }
+    let host = parsed.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+    let local = match host.parse::<IpAddr>() {
+        Ok(ip) => is_lan_ip(ip),
+        Err(_) => {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            // single-label names only resolve through the LAN's own DNS
+            host == "localhost" || (!host.is_empty() && !host.contains('.'))
+                || [".localhost", ".local", ".lan", ".home.arpa", ".internal"].iter().any(|suffix| host.ends_with(suffix))
+        },
+    };
+    if !local {
+        return Err(format!("webhook url {url} isn't a local or LAN address"))
+    }
+    Ok(())
+}
+
+fn is_lan_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local()
+            || ip.to_ipv4_mapped().is_some_and(|ip| is_lan_ip(IpAddr::V4(ip))),
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct HookConfig {
+    pub on: Vec<HookTrigger>,
+    #[serde(flatten)]
+    pub action: HookAction,
+    #[serde(default = "HookConfig::default_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default = "HookConfig::default_retries")]
+    pub retries: usize,
+}
+
+impl HookConfig {
+    fn default_timeout_secs() -> u64 {
+        10
+    }
+
+    fn default_retries() -> usize {
+        2
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct HooksConfig {
+    #[serde(default)]
+    pub hooks: Vec<HookConfig>,
+    /// How long the relay has to be down before `prolonged-failure` fires
+    #[serde(default = "HooksConfig::default_failure_after_secs")]
+    pub failure_after_secs: u64,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        HooksConfig {
+            hooks: vec![],
+            failure_after_secs: HooksConfig::default_failure_after_secs(),
+        }
+    }
+}
+
+impl HooksConfig {
+    fn default_failure_after_secs() -> u64 {
+        300
+    }
+}
+
+/// The JSON hooks receive
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum HookPayload {
+    NewCode {
+        code: String,
+    },
+    ProlongedFailure {
+        down_for_secs: u64,
+        error: String,
+    },
+    ValidationFailed {
+        id: Option<u64>,
+        error: String,
+    },
+    Recovered {
+        down_for_secs: u64,
+        code: String,
+    },
+}
+
+impl HookPayload {
+    pub fn trigger(&self) -> HookTrigger {
+        match self {
+            HookPayload::NewCode { .. } => HookTrigger::NewCode,
+            HookPayload::ProlongedFailure { .. } => HookTrigger::ProlongedFailure,
+            HookPayload::ValidationFailed { .. } => HookTrigger::ValidationFailed,
+            HookPayload::Recovered { .. } => HookTrigger::Recovered,
+        }
+    }
+}
+
+async fn run_action(action: &HookAction, http: &reqwest::Client, payload: &str) -> Result<(), RelayError> {
+    match action {
+        HookAction::Command { command } => {
+            let mut child = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stdin(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()?;
+            let mut stdin = child.stdin.take().unwrap();
+            // the command may not read its input at all
+            let _ = stdin.write_all(payload.as_bytes()).await;
+            drop(stdin);
+            let status = child.wait().await?;
+            if !status.success() {
+                return Err(RelayError::HookFailed(format!("`{command}` exited with {status}")))
+            }
+        },
+        HookAction::Webhook { url } => {
+            http.post(url)
+                .header("Content-Type", "application/json")
+                .body(payload.to_string())
+                .send().await?
+                .error_for_status()?;
+        },
+    }
+    Ok(())
+}
+
+async fn run_hook(hook: &HookConfig, http: &reqwest::Client, payload: &HookPayload) -> Result<(), RelayError> {
+    let payload = serde_json::to_string(payload)?;
+    let timeout = Duration::from_secs(hook.timeout_secs);
+    (|| async {
+        time::timeout(timeout, run_action(&hook.action, http, &payload)).await
+            .unwrap_or_else(|_| Err(RelayError::HookFailed(format!("timed out after {}s", hook.timeout_secs))))
+    })
+        .retry(&ExponentialBuilder::default().with_max_times(hook.retries))
+        .await
+}
+
+fn fire(hooks: &Arc<Vec<HookConfig>>, http: &reqwest::Client, payload: HookPayload) {
+    let trigger = payload.trigger();
+    for (index, hook) in hooks.iter().enumerate() {
+        if !hook.on.contains(&trigger) {
+            continue
+        }
+        let hooks = hooks.clone();
+        let http = http.clone();
+        let payload = payload.clone();
+        // a slow hook shouldn't hold up the others or miss events
+        tokio::spawn(async move {
+            if let Err(err) = run_hook(&hooks[index], &http, &payload).await {
+                println!("Hook {index} failed on {trigger:?}: {err}");
+            }
+        });
+    }
+}
+
+/// Turn relay events into hook runs until the event stream closes
+pub fn spawn_hooks(config: HooksConfig, events: broadcast::Receiver<RelayEvent>) -> JoinHandle<()> {
+    let hooks = Arc::new(config.hooks);
+    let failure_after = Duration::from_secs(config.failure_after_secs);
+    let http = reqwest::Client::builder().use_rustls_tls().build().unwrap();
+    tokio::spawn(follow_events(failure_after, events, move |payload| fire(&hooks, &http, payload)))
+}
+
+// what should fire for the relay's events, including prolonged-failure once it has been down too long
+async fn follow_events(failure_after: Duration, mut events: broadcast::Receiver<RelayEvent>, mut fire: impl FnMut(HookPayload)) {
+    // when the relay went down and why, and whether prolonged-failure already fired
+    let mut down: Option<(Instant, String, bool)> = None;
+    loop {
+        let prolonged_at = match &down {
+            Some((since, _, false)) => Some(*since + failure_after),
+            _ => None,
+        };
+        let event = select! {
+            event = events.recv() => event,
+            _ = time::sleep_until(prolonged_at.unwrap_or_else(Instant::now)), if prolonged_at.is_some() => {
+                if let Some((since, error, fired)) = &mut down {
+                    *fired = true;
+                    fire(HookPayload::ProlongedFailure { down_for_secs: since.elapsed().as_secs(), error: error.clone() });
+                }
+                continue
+            }
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        match event {
+            RelayEvent::Registered { code, is_new } => {
+                if let Some((since, _, true)) = down.take() {
+                    fire(HookPayload::Recovered { down_for_secs: since.elapsed().as_secs(), code: code.clone() });
+                }
+                if is_new {
+                    fire(HookPayload::NewCode { code });
+                }
+            },
+            RelayEvent::Disconnected { reason: error } | RelayEvent::RetryScheduled { error, .. } => match &mut down {
+                Some((_, last_error, _)) => *last_error = error,
+                None => down = Some((Instant::now(), error, false)),
+            },
+            RelayEvent::ValidationFailed { id, error } => fire(HookPayload::ValidationFailed { id, error }),
+            RelayEvent::Connecting { .. } | RelayEvent::ValidationServed { .. } | RelayEvent::Flapping { .. } => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{extract::State, routing::post, Router};
+    use tokio::{net::TcpListener, sync::{broadcast, mpsc}};
+
+    use crate::{events::RelayEvent, testing::TempDir};
+
+    use super::{check_lan_url, follow_events, run_hook, spawn_hooks, HookAction, HookConfig, HookPayload, HookTrigger, HooksConfig};
+
+    const FAILURE_AFTER: Duration = Duration::from_secs(300);
+
+    fn command_hook(on: &[HookTrigger], command: String) -> HookConfig {
+        HookConfig { on: on.to_vec(), action: HookAction::Command { command }, timeout_secs: 5, retries: 0 }
+    }
+
+    fn payload() -> HookPayload {
+        HookPayload::NewCode { code: "ABCD".to_string() }
+    }
+
+    /// What `follow_events` fires, with the sender for the events it follows
+    fn follow() -> (broadcast::Sender<RelayEvent>, mpsc::UnboundedReceiver<HookPayload>) {
+        let (events, _) = broadcast::channel(16);
+        let (fired, fired_recv) = mpsc::unbounded_channel();
+        tokio::spawn(follow_events(FAILURE_AFTER, events.subscribe(), move |payload| { let _ = fired.send(payload); }));
+        (events, fired_recv)
+    }
+
+    fn disconnected() -> RelayEvent {
+        RelayEvent::Disconnected { reason: "connection closed".to_string() }
+    }
+
+    fn registered(is_new: bool) -> RelayEvent {
+        RelayEvent::Registered { code: "ABCD".to_string(), is_new }
+    }
+
+    #[tokio::test]
+    async fn commands_get_the_payload_on_stdin() {
+        let dir = TempDir::new("hooks");
+        let path = dir.path().join("payload.json");
+        let hook = command_hook(&[HookTrigger::NewCode], format!("cat > {}", path.display()));
+
+        run_hook(&hook, &reqwest::Client::new(), &payload()).await.unwrap();
+        let written: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written, serde_json::json!({ "event": "new-code", "code": "ABCD" }));
+    }
+
+    #[tokio::test]
+    async fn webhooks_post_the_payload() {
+        let (received, mut received_recv) = mpsc::unbounded_channel::<String>();
+        let router = Router::new()
+            .route("/hook", post(|State(received): State<mpsc::UnboundedSender<String>>, body: String| async move { let _ = received.send(body); }))
+            .with_state(received);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        let hook = HookConfig { on: vec![HookTrigger::NewCode], action: HookAction::Webhook { url }, timeout_secs: 5, retries: 0 };
+
+        run_hook(&hook, &reqwest::Client::new(), &payload()).await.unwrap();
+        assert_eq!(received_recv.recv().await.unwrap(), r#"{"event":"new-code","code":"ABCD"}"#);
+    }
+
+    #[tokio::test]
+    async fn failing_hooks_are_retried_up_to_the_limit() {
+        let dir = TempDir::new("hooks");
+        let path = dir.path().join("runs");
+        let hook = HookConfig { retries: 1, ..command_hook(&[HookTrigger::NewCode], format!("echo run >> {}; exit 1", path.display())) };
+
+        assert!(run_hook(&hook, &reqwest::Client::new(), &payload()).await.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn slow_hooks_time_out() {
+        let hook = HookConfig { timeout_secs: 1, ..command_hook(&[HookTrigger::NewCode], "sleep 10".to_string()) };
+        let err = run_hook(&hook, &reqwest::Client::new(), &payload()).await.unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn only_hooks_for_the_event_fire() {
+        let dir = TempDir::new("hooks");
+        let path = dir.path().join("events");
+        let record = format!("cat >> {}; echo >> {}", path.display(), path.display());
+        let config = HooksConfig {
+            hooks: vec![command_hook(&[HookTrigger::ValidationFailed], record), command_hook(&[HookTrigger::NewCode], "exit 1".to_string())],
+            ..Default::default()
+        };
+        let (events, _) = broadcast::channel(16);
+        let hooks = spawn_hooks(config, events.subscribe());
+
+        events.send(RelayEvent::ValidationFailed { id: Some(3), error: "nope".to_string() }).unwrap();
+        events.send(registered(true)).unwrap();
+        while !std::fs::read_to_string(&path).is_ok_and(|events| events.ends_with('\n')) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{\"event\":\"validation-failed\",\"id\":3,\"error\":\"nope\"}\n");
+        hooks.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn prolonged_failure_then_recovery() {
+        let (events, mut fired) = follow();
+        events.send(registered(true)).unwrap();
+        assert!(matches!(fired.recv().await.unwrap(), HookPayload::NewCode { code } if code == "ABCD"));
+
+        events.send(disconnected()).unwrap();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        events.send(RelayEvent::RetryScheduled { error: "refused".to_string(), r#in: Duration::from_secs(30) }).unwrap();
+        tokio::time::sleep(FAILURE_AFTER - Duration::from_secs(61)).await;
+        assert!(fired.try_recv().is_err());
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(matches!(fired.try_recv().unwrap(), HookPayload::ProlongedFailure { down_for_secs: 300, error } if error == "refused"));
+        // only once per outage
+        tokio::time::sleep(FAILURE_AFTER * 2).await;
+        assert!(fired.try_recv().is_err());
+
+        events.send(registered(false)).unwrap();
+        assert!(matches!(fired.recv().await.unwrap(), HookPayload::Recovered { down_for_secs: 901, code } if code == "ABCD"));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(fired.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn short_outages_fire_nothing() {
+        let (events, mut fired) = follow();
+        events.send(disconnected()).unwrap();
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        events.send(registered(false)).unwrap();
+        tokio::time::sleep(FAILURE_AFTER * 2).await;
+        assert!(fired.try_recv().is_err());
+    }
+
+    #[test]
+    fn webhooks_only_go_to_the_lan() {
+        for url in ["http://127.0.0.1:8123/hook", "http://192.168.1.5/hook", "http://10.0.0.2/", "http://[::1]:80/", "http://[fd00::1]/",
+            "http://homeassistant:8123/api", "https://nas.local/hook", "http://localhost/hook", "http://printer.lan./"] {
+            assert!(check_lan_url(url).is_ok(), "{url}");
+        }
+        for url in ["https://hooks.example.com/x", "http://8.8.8.8/", "http://[2001:db8::1]/", "ftp://192.168.1.5/", "not a url"] {
+            assert!(check_lan_url(url).is_err(), "{url}");
+        }
+
+        let config: Result<HooksConfig, _> = serde_json::from_str(r#"{"hooks": [{"on": ["new-code"], "type": "webhook", "url": "https://hooks.example.com/x"}]}"#);
+        assert!(config.err().unwrap().to_string().contains("isn't a local or LAN address"));
+        let config: HooksConfig = serde_json::from_str(r#"{"hooks": [{"on": ["new-code"], "type": "webhook", "url": "http://192.168.1.5/hook"}]}"#).unwrap();
+        assert_eq!(config.hooks.len(), 1);
+    }
+}
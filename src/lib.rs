@@ -0,0 +1,42 @@
+
+#[cfg(not(any(feature = "ios", feature = "host")))]
+compile_error!("build.rs should have picked `ios` or `host`; build with `--features ios` for a device or `--features host` for anything else");
+
+use base64::engine::general_purpose;
+use base64::Engine;
+
+pub mod agent;
+#[cfg(feature = "ios")]
+mod c;
+pub mod client;
+pub mod commands;
+pub mod crash;
+pub mod device;
+pub mod doctor;
+pub mod error;
+pub mod events;
+pub mod hooks;
+pub mod local_api;
+pub mod nac;
+pub mod pool;
+pub mod protocol;
+pub mod provider;
+pub mod qr;
+pub mod relay;
+pub mod self_test;
+pub mod server;
+pub mod stdio;
+pub mod supervisor;
+#[cfg(test)]
+mod testing;
+pub mod util;
+
+pub use provider::{ProviderBuilder, RelayProvider};
+
+pub fn base64_encode(data: &[u8]) -> String {
+    general_purpose::STANDARD.encode(data)
+}
+
+pub fn base64_decode(data: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    general_purpose::STANDARD.decode(data)
+}
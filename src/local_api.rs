@@ -0,0 +1,332 @@
+//! HTTP and websocket API on the provider itself, so clients on the same machine or network can
+//! get validation data straight from the device instead of through a relay.
+//!
+//! Commands use the relay's bridge route (`POST /api/v1/bridge/:command` with the token as the
+//! bearer), so a `RelayClient` pointed at the device works unchanged.
+
+use std::{sync::Arc, time::{Duration, UNIX_EPOCH}};
+
+use axum::{body::Bytes, extract::{ws::{Message, WebSocket}, Path, State, WebSocketUpgrade}, http::{header, HeaderMap, StatusCode}, response::{IntoResponse, Response}, routing::{get, post}, Json, Router};
+use backon::ExponentialBuilder;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::{net::TcpListener, task::JoinHandle};
+
+use crate::{agent::token_matches, commands::CommandHandler, error::RelayError, protocol::{CommandData, RelayCommand}, qr::{render_png, QrConfig}, relay::Relay, util::{Resource, ResourceManagerBuilder, ResourceState, StateTransition}};
+
+const QR_PNG_SCALE: usize = 8;
+
+#[derive(Error, Debug)]
+pub enum LocalApiError {
+    #[error("Not registered with the relay yet")]
+    NotRegistered,
+    #[error("Bad token")]
+    Unauthorized,
+    #[error("Unknown command {0}")]
+    UnknownCommand(String),
+    #[error("JSON error: {0}")]
+    JSONError(#[from] serde_json::Error),
+    #[error("{0}")]
+    RelayError(#[from] RelayError),
+}
+
+impl IntoResponse for LocalApiError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            LocalApiError::NotRegistered => StatusCode::SERVICE_UNAVAILABLE,
+            LocalApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            LocalApiError::UnknownCommand(_) => StatusCode::NOT_FOUND,
+            LocalApiError::JSONError(_) => StatusCode::BAD_REQUEST,
+            LocalApiError::RelayError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, Json(json!({ "error": self.to_string() }))).into_response()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct LocalApiConfig {
+    pub bind: String,
+    /// Clients must present this as a bearer token
+    pub token: String,
+}
+
+impl Default for LocalApiConfig {
+    fn default() -> Self {
+        LocalApiConfig {
+            bind: "127.0.0.1:7433".to_string(),
+            token: String::new(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Pairing {
+    code: String,
+    /// What the pairing QR encodes
+    contents: String,
+}
+
+struct LocalApi {
+    handler: Arc<CommandHandler>,
+    // absent when serving without a relay connection
+    relay: Option<Relay>,
+    qr: QrConfig,
+    token: String,
+}
+
+impl LocalApi {
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), LocalApiError> {
+        let given = headers.get("Authorization").and_then(|value| value.to_str().ok()).and_then(|value| value.strip_prefix("Bearer "));
+        match given {
+            Some(given) if token_matches(given.as_bytes(), self.token.as_bytes()) => Ok(()),
+            _ => Err(LocalApiError::Unauthorized),
+        }
+    }
+
+    async fn contents(&self) -> Result<Pairing, LocalApiError> {
+        let relay = self.relay.as_ref().ok_or(LocalApiError::NotRegistered)?;
+        let state = relay.state.lock().await;
+        let code = state.as_ref().ok_or(LocalApiError::NotRegistered)?.code.clone();
+        Ok(Pairing { contents: self.qr.contents(&code), code })
+    }
+}
+
+fn state_json(state: &ResourceState<RelayError>) -> Value {
+    match state {
+        ResourceState::Generated => json!({ "state": "generated" }),
+        ResourceState::Generating => json!({ "state": "generating" }),
+        ResourceState::Waiting => json!({ "state": "waiting" }),
+        ResourceState::Lost(error) => json!({ "state": "lost", "error": error.to_string() }),
+        ResourceState::Failed(failure) => json!({ "state": "failed", "error": failure.error.to_string(), "retry_wait": failure.retry_wait }),
+    }
+}
+
+fn transition_json(transition: &StateTransition<RelayError>) -> Value {
+    let mut entry = state_json(&transition.state);
+    entry["attempt"] = json!(transition.attempt);
+    entry["at_ms"] = json!(transition.at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64);
+    entry
+}
+
+pub fn router(handler: Arc<CommandHandler>, relay: Option<Relay>, qr: QrConfig, token: String) -> Router {
+    Router::new()
+        .route("/api/v1/pairing", get(pairing))
+        .route("/api/v1/pairing/qr.png", get(pairing_qr))
+        .route("/api/v1/status", get(status))
+        .route("/api/v1/bridge/:command", post(command))
+        .route("/api/v1/ws", get(command_socket))
+        .with_state(Arc::new(LocalApi { handler, relay, qr, token }))
+}
+
+fn missing_token() -> RelayError {
+    RelayError::BadCommand("refusing to run the local API without a token".to_string())
+}
+
+pub async fn serve_local_api(config: &LocalApiConfig, handler: Arc<CommandHandler>, relay: Option<Relay>, qr: QrConfig) -> Result<(), RelayError> {
+    if config.token.is_empty() {
+        return Err(missing_token())
+    }
+    let listener = TcpListener::bind(&config.bind).await?;
+    println!("Local API listening on {}", config.bind);
+    axum::serve(listener, router(handler, relay, qr, config.token.clone())).await?;
+    Ok(())
+}
+
+/// The local API as a supervised resource, so a port that's taken is retried instead of given up on
+pub struct LocalApiResource {
+    config: LocalApiConfig,
+    handler: Arc<CommandHandler>,
+    relay: Option<Relay>,
+    qr: QrConfig,
+}
+
+impl LocalApiResource {
+    pub fn new(config: LocalApiConfig, handler: Arc<CommandHandler>, relay: Option<Relay>, qr: QrConfig) -> LocalApiResource {
+        LocalApiResource { config, handler, relay, qr }
+    }
+
+    pub fn manager_builder() -> ResourceManagerBuilder<ExponentialBuilder> {
+        ResourceManagerBuilder::new()
+            .backoff(ExponentialBuilder::default()
+                .with_max_delay(Duration::from_secs(30))
+                .with_max_times(usize::MAX))
+    }
+}
+
+impl Resource for LocalApiResource {
+    type Error = RelayError;
+
+    async fn generate(self: &Arc<Self>) -> Result<JoinHandle<()>, RelayError> {
+        if self.config.token.is_empty() {
+            return Err(RelayError::DoNotRetry(Box::new(missing_token())))
+        }
+        let listener = TcpListener::bind(&self.config.bind).await?;
+        println!("Local API listening on {}", self.config.bind);
+        let app = router(self.handler.clone(), self.relay.clone(), self.qr.clone(), self.config.token.clone());
+        Ok(tokio::spawn(async move {
+            if let Err(err) = axum::serve(listener, app).await {
+                println!("Local API failed: {err}");
+            }
+        }))
+    }
+}
+
+async fn pairing(State(api): State<Arc<LocalApi>>, headers: HeaderMap) -> Result<Json<Pairing>, LocalApiError> {
+    api.authorize(&headers)?;
+    Ok(Json(api.contents().await?))
+}
+
+async fn pairing_qr(State(api): State<Arc<LocalApi>>, headers: HeaderMap) -> Result<Response, LocalApiError> {
+    api.authorize(&headers)?;
+    let png = render_png(&api.contents().await?.contents, QR_PNG_SCALE)?;
+    Ok(([(header::CONTENT_TYPE, "image/png")], png).into_response())
+}
+
+/// The relay connection's state and how it got there, and the latest self-test
+async fn status(State(api): State<Arc<LocalApi>>, headers: HeaderMap) -> Result<Json<Value>, LocalApiError> {
+    api.authorize(&headers)?;
+    let relay = api.relay.as_ref().ok_or(LocalApiError::NotRegistered)?;
+    let history: Vec<Value> = relay.state_history().iter().map(transition_json).collect();
+    let self_test = relay.self_test.as_ref().and_then(|self_test| self_test.report());
+    Ok(Json(json!({ "state": state_json(&relay.state()), "flaps": relay.flaps(), "history": history, "self_test": self_test })))
+}
+
+async fn command(State(api): State<Arc<LocalApi>>, Path(command): Path<String>, headers: HeaderMap, body: Bytes) -> Result<Json<CommandData>, LocalApiError> {
+    api.authorize(&headers)?;
+    let data = if body.is_empty() { None } else { Some(serde_json::from_slice(&body)?) };
+    let command = RelayCommand { command, id: None, data };
+    let response = api.handler.handle(&command).await.ok_or(LocalApiError::UnknownCommand(command.command))?;
+    Ok(Json(response))
+}
+
+async fn command_socket(State(api): State<Arc<LocalApi>>, headers: HeaderMap, upgrade: WebSocketUpgrade) -> Result<Response, LocalApiError> {
+    api.authorize(&headers)?;
+    Ok(upgrade.on_upgrade(move |socket| handle_socket(api, socket)))
+}
+
+// speaks the relay's provider protocol: commands in, responses with the same id out
+async fn handle_socket(api: Arc<LocalApi>, mut socket: WebSocket) {
+    while let Some(Ok(msg)) = socket.recv().await {
+        let Message::Text(text) = msg else { continue };
+        let Ok(command) = serde_json::from_str::<RelayCommand>(&text) else {
+            println!("Bad message on local API socket: {text}");
+            continue
+        };
+        let response = match command.command.as_str() {
+            "ping" => RelayCommand { command: "pong".to_string(), id: None, data: None },
+            _ => {
+                let data = api.handler.handle(&command).await
+                    .unwrap_or_else(|| CommandData::Error { error: format!("Unknown command {}", command.command), nac_code: None, transient: false });
+                RelayCommand { command: "response".to_string(), id: command.id, data: Some(data) }
+            }
+        };
+        if socket.send(Message::Text(serde_json::to_string(&response).unwrap())).await.is_err() {
+            break
+        }
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use reqwest::{RequestBuilder, StatusCode};
+    use serde_json::Value;
+    use tokio::net::TcpListener;
+
+    use crate::{qr::QrConfig, relay::Relay, testing::{fake_handler, fixture_device_info, spawn_relay_server, start_provider, TempDir}};
+
+    use super::router;
+
+    const TOKEN: &str = "local-token";
+
+    /// The local API on a free port, answering with the fake backend; returns its http:// root
+    async fn spawn_local_api(relay: Option<Relay>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(fake_handler(fixture_device_info()), relay, QrConfig::default(), TOKEN.to_string());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{addr}/api/v1")
+    }
+
+    async fn send(request: RequestBuilder) -> (StatusCode, Value) {
+        let response = request.send().await.unwrap();
+        (response.status(), response.json().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn requires_the_token() {
+        let base = spawn_local_api(None).await;
+        let http = reqwest::Client::new();
+
+        for path in ["/pairing", "/pairing/qr.png", "/status"] {
+            assert_eq!(send(http.get(format!("{base}{path}"))).await.0, StatusCode::UNAUTHORIZED, "{path}");
+            assert_eq!(send(http.get(format!("{base}{path}")).bearer_auth("wrong")).await.0, StatusCode::UNAUTHORIZED, "{path}");
+        }
+        let (status, body) = send(http.post(format!("{base}/bridge/get-version-info")).bearer_auth("wrong")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"], "Bad token");
+
+        let Err(tokio_tungstenite::tungstenite::Error::Http(response)) = tokio_tungstenite::connect_async(format!("{}/ws", base.replacen("http://", "ws://", 1))).await else {
+            panic!("socket opened without a token")
+        };
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn bridge_runs_commands() {
+        let base = spawn_local_api(None).await;
+        let http = reqwest::Client::new();
+
+        let (status, body) = send(http.post(format!("{base}/bridge/get-version-info")).bearer_auth(TOKEN)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["versions"]["hardware_version"], "iPhone10,1");
+
+        let (status, body) = send(http.post(format!("{base}/bridge/get-validation-data")).bearer_auth(TOKEN)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["data"].as_str().is_some_and(|data| !data.is_empty()), "{body}");
+
+        let (status, body) = send(http.post(format!("{base}/bridge/reboot")).bearer_auth(TOKEN)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "Unknown command reboot");
+
+        let (status, _) = send(http.post(format!("{base}/bridge/sign-payload")).bearer_auth(TOKEN).body("{not json")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn status_and_pairing_need_a_relay() {
+        let base = spawn_local_api(None).await;
+        let http = reqwest::Client::new();
+        for path in ["/pairing", "/status"] {
+            let (status, body) = send(http.get(format!("{base}{path}")).bearer_auth(TOKEN)).await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{path}");
+            assert_eq!(body["error"], "Not registered with the relay yet");
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_the_relay_connection() {
+        let dir = TempDir::new("local-api");
+        let (_server, relay_base) = spawn_relay_server(&dir).await;
+        let (relay, code) = start_provider(&relay_base, fake_handler(fixture_device_info())).await;
+        let base = spawn_local_api(Some(relay)).await;
+        let http = reqwest::Client::new();
+
+        let (status, body) = send(http.get(format!("{base}/status")).bearer_auth(TOKEN)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["state"]["state"], "generated");
+        assert_eq!(body["flaps"], 0);
+        let history = body["history"].as_array().unwrap();
+        assert_eq!(history.last().unwrap()["state"], "generated");
+        assert!(history.iter().any(|entry| entry["state"] == "generating"), "{body}");
+
+        let (status, body) = send(http.get(format!("{base}/pairing")).bearer_auth(TOKEN)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((body["code"].as_str(), body["contents"].as_str()), (Some(code.as_str()), Some(code.as_str())));
+
+        let response = http.get(format!("{base}/pairing/qr.png")).bearer_auth(TOKEN).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "image/png");
+        assert!(response.bytes().await.unwrap().starts_with(b"\x89PNG"));
+    }
+}
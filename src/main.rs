@@ -1,36 +1,51 @@
+use std::{path::PathBuf, sync::{Arc, Mutex}};
 
-mod c;
-mod error;
-mod nac;
-mod relay;
-mod util;
-
-use std::sync::Arc;
-
-use base64::engine::general_purpose;
-use base64::Engine;
-use nac::generate_validation_data;
-use relay::{Relay, RelayResource, RelayState};
+use relayserver::{agent::{serve_agent, AgentConfig}, commands::CommandHandler, crash::CrashReportConfig, device::DeviceInfoConfig, doctor::{run_doctor, DoctorOptions}, error::RelayError, hooks::HooksConfig, local_api::{serve_local_api, LocalApiConfig}, nac::NacConfig, protocol::RelayState, provider::{StateStore, DEFAULT_RELAY_URL}, qr::QrConfig, stdio::{serve_stdio, take_stdout}, ProviderBuilder};
 use serde::{Deserialize, Serialize};
 use tokio::{fs, sync::broadcast};
 
-
-pub fn base64_encode(data: &[u8]) -> String {
-    general_purpose::STANDARD.encode(data)
-}
-
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct RelayConfig {
     url: String,
     state: Option<RelayState>,
+    #[serde(default)]
+    device_info: DeviceInfoConfig,
+    #[serde(default)]
+    nac: NacConfig,
+    #[serde(default)]
+    agent: AgentConfig,
+    #[serde(default)]
+    hooks: HooksConfig,
+    #[serde(default)]
+    qr: QrConfig,
+    /// Off unless configured
+    local_api: Option<LocalApiConfig>,
+    #[serde(default)]
+    crash_reports: CrashReportConfig,
+    /// Generate validation data before registering, and stay unregistered while that fails
+    #[serde(default)]
+    self_test: bool,
+    /// Let the fake NAC backend serve a self-hosted relay
+    #[serde(default)]
+    allow_fake_nac: bool,
 }
 
-impl RelayConfig {
-    async fn from_relay(relay: &Relay) -> RelayConfig {
-        RelayConfig {
-            url: relay.url.lock().await.clone(),
-            state: relay.state.lock().await.clone(),
-        }
+/// Keeps the registration in config.json alongside the rest of the config
+struct ConfigStateStore {
+    path: PathBuf,
+    config: Mutex<RelayConfig>,
+}
+
+impl StateStore for ConfigStateStore {
+    fn load(&self) -> Option<RelayState> {
+        self.config.lock().unwrap().state.clone()
+    }
+
+    fn save(&self, state: &RelayState) -> Result<(), RelayError> {
+        let mut config = self.config.lock().unwrap();
+        config.state = Some(state.clone());
+        std::fs::write(&self.path, serde_json::to_string(&*config)?)?;
+        Ok(())
     }
 }
 
@@ -39,34 +54,115 @@ async fn main() {
     let config_path = "config.json";
 
     let mut config: Option<RelayConfig> = None;
-    if let Ok(read) = fs::read_to_string(config_path).await {
-        if let Ok(item) = serde_json::from_str(&read) {
-            config = Some(item);
+    let mut config_error = None;
+    match fs::read_to_string(config_path).await {
+        Ok(read) => match serde_json::from_str(&read) {
+            Ok(item) => config = Some(item),
+            Err(err) => config_error = Some(err.to_string()),
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {},
+        Err(err) => config_error = Some(err.to_string()),
+    }
+
+    let config = config.unwrap_or_else(|| RelayConfig {
+        url: DEFAULT_RELAY_URL.to_string(),
+        state: None,
+        device_info: DeviceInfoConfig::default(),
+        nac: NacConfig::default(),
+        agent: AgentConfig::default(),
+        hooks: HooksConfig::default(),
+        qr: QrConfig::default(),
+        local_api: None,
+        crash_reports: CrashReportConfig::default(),
+        self_test: false,
+        allow_fake_nac: false,
+    });
+
+    // check the device setup piece by piece instead of starting
+    if std::env::args().nth(1).as_deref() == Some("doctor") {
+        let report = run_doctor(&DoctorOptions {
+            config_path: PathBuf::from(config_path),
+            config_error,
+            relay_url: config.url.clone(),
+            nac: config.nac.clone(),
+            device_info: config.device_info.clone(),
+        }).await;
+        if std::env::args().any(|arg| arg == "--json") {
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        } else {
+            report.print();
         }
+        std::process::exit(if report.passed { 0 } else { 1 });
+    }
+
+    // running on defaults would save them over the user's file on the first registration
+    if let Some(err) = config_error {
+        println!("Failed to load {config_path}: {err}");
+        std::process::exit(1);
     }
 
-    let url = config.as_ref().map(|i| i.url.clone()).unwrap_or_else(|| "wss://registration-relay.beeper.com/api/v1/provider".to_string());
-    let relay = RelayResource::new(url, config.as_ref().and_then(|i| i.state.clone()));
-
-    let mut to_refresh = relay.generated_signal.subscribe();
-    let reconn_conn = Arc::downgrade(&relay);
-    tokio::spawn(async move {
-        loop {
-            match to_refresh.recv().await {
-                Ok(()) => {
-                    let Some(conn) = reconn_conn.upgrade() else { break };
-                    // update keys
-                    let state = RelayConfig::from_relay(&conn).await;
-                    std::fs::write(config_path, serde_json::to_string(&state).unwrap()).unwrap();
-                },
-                Err(broadcast::error::RecvError::Lagged(_)) => continue,
-                Err(broadcast::error::RecvError::Closed) => break,
-            }
+    if std::env::args().nth(1).as_deref() == Some("nac-agent") {
+        if matches!(config.nac, NacConfig::Remote { .. }) {
+            println!("nac-agent mode needs a local NAC backend, not another agent");
+            std::process::exit(1);
         }
-    });
+        if let Err(err) = serve_agent(&config.agent, config.nac.build()).await {
+            println!("NAC agent failed: {err}");
+            std::process::exit(1);
+        }
+        return
+    }
 
+    let device_info = match config.device_info.build() {
+        Ok(device_info) => device_info,
+        Err(err) => {
+            println!("Failed to set up device info: {err}");
+            std::process::exit(1);
+        }
+    };
 
-    
+    // driven by a parent process over stdin/stdout
+    if std::env::args().nth(1).as_deref() == Some("--stdio") {
+        let output = take_stdout().expect("Failed to set up stdio");
+        let (events, _) = broadcast::channel(99);
+        let handler = Arc::new(CommandHandler::new(config.nac.build(), device_info, events));
+        serve_stdio(handler, output).await.expect("stdio mode failed");
+        return
+    }
+
+    // serve LAN clients directly, with no relay connection at all
+    if std::env::args().nth(1).as_deref() == Some("local") {
+        let (events, _) = broadcast::channel(99);
+        let handler = Arc::new(CommandHandler::new(config.nac.build(), device_info, events));
+        let local_api = config.local_api.clone().unwrap_or_default();
+        serve_local_api(&local_api, handler, None, config.qr.clone()).await.expect("Local API failed");
+        return
+    }
+
+    let mut builder = ProviderBuilder::new()
+        .url(config.url.clone())
+        .nac_backend(config.nac.build())
+        .device_info(device_info)
+        .hooks(config.hooks.clone())
+        .qr(config.qr.clone())
+        .crash_reports(config.crash_reports.clone())
+        .self_test(config.self_test)
+        .allow_fake_nac(config.allow_fake_nac);
+    if let Some(local_api) = config.local_api.clone() {
+        builder = builder.local_api(local_api);
+    }
+    let started = builder
+        .state_store(Arc::new(ConfigStateStore { path: PathBuf::from(config_path), config: Mutex::new(config) }))
+        .start().await;
+    let provider = match started {
+        Ok(provider) => provider,
+        Err(err) => {
+            println!("Failed to start relay provider: {err}");
+            std::process::exit(1);
+        }
+    };
 
-    loop { }
+    tokio::signal::ctrl_c().await.expect("Failed to listen for ctrl-c");
+    println!("Shutting down");
+    provider.stop().await;
 }
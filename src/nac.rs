@@ -1,10 +1,181 @@
 
 use std::io::Cursor;
+#[cfg(feature = "host")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::c::{nac_init_rs, nac_key_establishment_rs, nac_sign_rs};
+#[cfg(feature = "ios")]
+use crate::c::{nac_init_rs, nac_key_establishment_rs, nac_reset_port_rs, nac_sign_rs};
+use crate::agent::RemoteNacBackend;
+use crate::pool::NacPool;
 use crate::error::RelayError;
+use crate::util::{Resource, ResourceManagerBuilder};
+use backon::ExponentialBuilder;
+use futures::{future::BoxFuture, FutureExt};
 use plist::{Data, Error};
 use serde::{Serialize, Deserialize};
+use tokio::{sync::OnceCell, task::JoinHandle};
+
+
+
+/// Something that can run the three NAC phases; `ctx` is only meaningful to the backend that issued it.
+pub trait NacBackend: Send + Sync {
+    fn init<'a>(&'a self, cert: &'a [u8]) -> BoxFuture<'a, Result<(u64, Vec<u8>), RelayError>>;
+
+    fn key_establishment<'a>(&'a self, ctx: u64, response: &'a [u8]) -> BoxFuture<'a, Result<(), RelayError>>;
+
+    fn sign<'a>(&'a self, ctx: u64, data: &'a [u8]) -> BoxFuture<'a, Result<Vec<u8>, RelayError>>;
+
+    // forget any cached connection so the next call reconnects
+    fn reset(&self) -> BoxFuture<'_, ()>;
+
+    /// Init and key establishment, returning a context ready to sign with.
+    /// Runs the session handshake with Apple unless the backend has no use for it.
+    fn establish(&self) -> BoxFuture<'_, Result<u64, RelayError>> {
+        establish_session(self).boxed()
+    }
+
+    /// Whether the signatures are made up, so must never reach real clients
+    fn is_fake(&self) -> bool {
+        false
+    }
+}
+
+/// Talks to absd on this device over mach IPC
+#[cfg(feature = "ios")]
+pub struct AbsdBackend;
+
+#[cfg(feature = "ios")]
+impl NacBackend for AbsdBackend {
+    fn init<'a>(&'a self, cert: &'a [u8]) -> BoxFuture<'a, Result<(u64, Vec<u8>), RelayError>> {
+        async move {
+            let mut output_req = vec![];
+            let ctx = nac_init_rs(cert, &mut output_req)?;
+            Ok((ctx, output_req))
+        }.boxed()
+    }
+
+    fn key_establishment<'a>(&'a self, ctx: u64, response: &'a [u8]) -> BoxFuture<'a, Result<(), RelayError>> {
+        async move { nac_key_establishment_rs(ctx, response) }.boxed()
+    }
+
+    fn sign<'a>(&'a self, ctx: u64, data: &'a [u8]) -> BoxFuture<'a, Result<Vec<u8>, RelayError>> {
+        async move { nac_sign_rs(ctx, data) }.boxed()
+    }
+
+    fn reset(&self) -> BoxFuture<'_, ()> {
+        async { nac_reset_port_rs() }.boxed()
+    }
+}
+
+/// Stand-in for hosts without absd. Its signatures are recognisably fake and Apple will reject them;
+/// it exists so the daemon and its clients can be exercised end to end anywhere.
+#[cfg(feature = "host")]
+#[derive(Default)]
+pub struct FakeNacBackend {
+    next_ctx: AtomicU64,
+}
+
+#[cfg(feature = "host")]
+impl FakeNacBackend {
+    const MAGIC: &'static [u8] = b"FAKENAC";
+}
+
+#[cfg(feature = "host")]
+impl NacBackend for FakeNacBackend {
+    fn init<'a>(&'a self, _cert: &'a [u8]) -> BoxFuture<'a, Result<(u64, Vec<u8>), RelayError>> {
+        async move {
+            let ctx = self.next_ctx.fetch_add(1, Ordering::Relaxed);
+            Ok((ctx, Self::MAGIC.to_vec()))
+        }.boxed()
+    }
+
+    fn key_establishment<'a>(&'a self, _ctx: u64, _response: &'a [u8]) -> BoxFuture<'a, Result<(), RelayError>> {
+        async { Ok(()) }.boxed()
+    }
+
+    fn sign<'a>(&'a self, ctx: u64, data: &'a [u8]) -> BoxFuture<'a, Result<Vec<u8>, RelayError>> {
+        async move {
+            let mut signature = Self::MAGIC.to_vec();
+            signature.extend_from_slice(&ctx.to_be_bytes());
+            signature.extend_from_slice(data);
+            Ok(signature)
+        }.boxed()
+    }
+
+    fn reset(&self) -> BoxFuture<'_, ()> {
+        async {}.boxed()
+    }
+
+    // Apple would refuse our session request, so skip the handshake entirely
+    fn establish(&self) -> BoxFuture<'_, Result<u64, RelayError>> {
+        async { Ok(self.init(&[]).await?.0) }.boxed()
+    }
+
+    fn is_fake(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum NacConfig {
+    #[cfg(feature = "ios")]
+    Absd,
+    #[cfg(feature = "host")]
+    Fake,
+    /// A relayserver in nac-agent mode on another device
+    Remote {
+        url: String,
+        token: String,
+    },
+    /// Load balance across several backends with failover
+    Pool {
+        members: Vec<NacConfig>,
+        #[serde(default = "NacConfig::default_eject_after")]
+        eject_after: u32,
+        #[serde(default = "NacConfig::default_probe_interval_secs")]
+        probe_interval_secs: u64,
+    },
+}
+
+impl Default for NacConfig {
+    fn default() -> Self {
+        #[cfg(feature = "ios")]
+        return NacConfig::Absd;
+        #[cfg(all(feature = "host", not(feature = "ios")))]
+        return NacConfig::Fake;
+        // lib.rs already refuses to build this way
+        #[cfg(not(any(feature = "ios", feature = "host")))]
+        unreachable!()
+    }
+}
+
+impl NacConfig {
+    fn default_eject_after() -> u32 {
+        3
+    }
+
+    fn default_probe_interval_secs() -> u64 {
+        60
+    }
+
+    pub fn build(&self) -> Arc<dyn NacBackend> {
+        match self {
+            #[cfg(feature = "ios")]
+            NacConfig::Absd => Arc::new(AbsdBackend),
+            #[cfg(feature = "host")]
+            NacConfig::Fake => Arc::new(FakeNacBackend::default()),
+            NacConfig::Remote { url, token } => Arc::new(RemoteNacBackend::new(url.clone(), token.clone())),
+            NacConfig::Pool { members, eject_after, probe_interval_secs } => Arc::new(NacPool::new(
+                members.iter().map(|member| member.build()).collect(),
+                *eject_after,
+                Duration::from_secs(*probe_interval_secs),
+            )),
+        }
+    }
+}
 
 
 #[derive(Serialize)]
@@ -31,34 +202,204 @@ pub fn plist_to_buf<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, Error> {
     Ok(buf)
 }
 
-pub async fn generate_validation_data() -> Result<Vec<u8>, RelayError> {
-    let client = reqwest::Client::builder()
+pub async fn generate_validation_data(backend: &dyn NacBackend) -> Result<Vec<u8>, RelayError> {
+    sign_payload(backend, &[]).await
+}
+
+/// Sign caller-supplied bytes in a freshly established NAC session
+pub async fn sign_payload(backend: &dyn NacBackend, payload: &[u8]) -> Result<Vec<u8>, RelayError> {
+    match sign_in_session(backend, payload).await {
+        // the session context died with absd, so the whole session has to be redone on the new port
+        Err(err) if err.is_backend_lost() => {
+            println!("NAC backend went away ({err}), reconnecting and retrying once");
+            backend.reset().await;
+            sign_in_session(backend, payload).await
+        },
+        result => result,
+    }
+}
+
+async fn sign_in_session(backend: &dyn NacBackend, payload: &[u8]) -> Result<Vec<u8>, RelayError> {
+    let ctx = backend.establish().await?;
+    backend.sign(ctx, payload).await
+}
+
+// Apple's validation cert is static, so fetch it once per run
+static VALIDATION_CERT: OnceCell<Vec<u8>> = OnceCell::const_new();
+
+fn apple_client() -> reqwest::Client {
+    reqwest::Client::builder()
         .danger_accept_invalid_certs(true)
         .use_rustls_tls()
         .build()
-        .unwrap();
+        .unwrap()
+}
 
-    let key = client.get("http://static.ess.apple.com/identity/validation/cert-1.0.plist")
-        .send().await?;
-    let response: CertsResponse = plist::from_bytes(&key.bytes().await?)?;
-    let certs: Vec<u8> = response.cert.into();
+pub async fn validation_cert() -> Result<&'static [u8], RelayError> {
+    let cert = VALIDATION_CERT.get_or_try_init(|| async {
+        let key = apple_client().get("http://static.ess.apple.com/identity/validation/cert-1.0.plist")
+            .send().await?;
+        let response: CertsResponse = plist::from_bytes(&key.bytes().await?)?;
+        Ok::<_, RelayError>(response.cert.into())
+    }).await?;
+    Ok(cert)
+}
+
+/// Run NAC init and key establishment against Apple, returning a context ready to sign with
+pub async fn establish_session<B: NacBackend + ?Sized>(backend: &B) -> Result<u64, RelayError> {
+    let (ctx, output_req) = backend.init(validation_cert().await?).await?;
+    let output = initialize_validation(output_req).await?;
+    backend.key_establishment(ctx, &output).await?;
 
-    let mut output_req = vec![];
-    let ctx = nac_init_rs(&certs, &mut output_req)?;
+    Ok(ctx)
+}
 
+/// Trade the session request from NAC init for Apple's session info
+pub async fn initialize_validation(session_request: Vec<u8>) -> Result<Vec<u8>, RelayError> {
     let init = SessionInfoRequest {
-        session_info_request: output_req.into()
+        session_info_request: session_request.into()
     };
 
     let info = plist_to_buf(&init)?;
-    let activation = client.post("https://identity.ess.apple.com/WebObjects/TDIdentityService.woa/wa/initializeValidation")
+    let activation = apple_client().post("https://identity.ess.apple.com/WebObjects/TDIdentityService.woa/wa/initializeValidation")
         .body(info)
         .send().await?;
 
     let response: SessionInfoResponse = plist::from_bytes(&activation.bytes().await?)?;
-    let output: Vec<u8> = response.session_info.into();
-    nac_key_establishment_rs(ctx, &output)?;
+    Ok(response.session_info.into())
+}
+
+/// Fetches Apple's validation cert, so nothing that needs a session starts before it's cached
+pub struct CertCacheResource;
+
+impl CertCacheResource {
+    pub fn manager_builder() -> ResourceManagerBuilder<ExponentialBuilder> {
+        ResourceManagerBuilder::new()
+            .backoff(ExponentialBuilder::default()
+                .with_max_delay(Duration::from_secs(60))
+                .with_max_times(usize::MAX))
+    }
+}
+
+impl Resource for CertCacheResource {
+    type Error = RelayError;
+
+    // the cert never changes, so once it's cached there's nothing left to do
+    async fn generate(self: &Arc<Self>) -> Result<JoinHandle<()>, RelayError> {
+        validation_cert().await?;
+        Ok(tokio::spawn(std::future::pending()))
+    }
+}
+
+/// Up while the NAC backend can establish sessions; rechecked every few minutes so a dead backend
+/// takes down whatever depends on it
+pub struct NacSessionResource {
+    nac: Arc<dyn NacBackend>,
+}
+
+impl NacSessionResource {
+    pub fn new(nac: Arc<dyn NacBackend>) -> NacSessionResource {
+        NacSessionResource { nac }
+    }
+
+    pub fn manager_builder() -> ResourceManagerBuilder<ExponentialBuilder> {
+        ResourceManagerBuilder::new()
+            .backoff(ExponentialBuilder::default()
+                .with_max_delay(Duration::from_secs(60))
+                .with_max_times(usize::MAX))
+            .health_interval(Duration::from_secs(300))
+            .health_timeout(Duration::from_secs(60))
+    }
+
+    async fn establish(&self) -> Result<(), RelayError> {
+        match self.nac.establish().await {
+            // reconnect to the backend for the next attempt
+            Err(err) if err.is_backend_lost() => {
+                self.nac.reset().await;
+                Err(err)
+            },
+            result => result.map(|_| ()),
+        }
+    }
+}
+
+impl Resource for NacSessionResource {
+    type Error = RelayError;
 
-    
-    Ok(nac_sign_rs(ctx, &[])?)
-}
\ No newline at end of file
+    async fn generate(self: &Arc<Self>) -> Result<JoinHandle<()>, RelayError> {
+        self.establish().await?;
+        Ok(tokio::spawn(std::future::pending()))
+    }
+
+    async fn health_check(self: &Arc<Self>) -> Result<(), RelayError> {
+        self.establish().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use futures::{future::BoxFuture, FutureExt};
+
+    use crate::error::{NacErrorCode, RelayError};
+
+    use super::{generate_validation_data, NacBackend};
+
+    /// Signs until `port_deaths` runs out of failures, failing the way absd does when its port dies
+    #[derive(Default)]
+    struct DyingBackend {
+        port_deaths: AtomicU32,
+        sessions: AtomicU32,
+        resets: AtomicU32,
+    }
+
+    impl NacBackend for DyingBackend {
+        fn init<'a>(&'a self, _cert: &'a [u8]) -> BoxFuture<'a, Result<(u64, Vec<u8>), RelayError>> {
+            async { Ok((self.sessions.fetch_add(1, Ordering::SeqCst) as u64, vec![])) }.boxed()
+        }
+
+        fn key_establishment<'a>(&'a self, _ctx: u64, _response: &'a [u8]) -> BoxFuture<'a, Result<(), RelayError>> {
+            async { Ok(()) }.boxed()
+        }
+
+        fn sign<'a>(&'a self, ctx: u64, _data: &'a [u8]) -> BoxFuture<'a, Result<Vec<u8>, RelayError>> {
+            async move {
+                if self.port_deaths.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| left.checked_sub(1)).is_ok() {
+                    return Err(RelayError::NacError(NacErrorCode::SendInvalidDest))
+                }
+                Ok(ctx.to_be_bytes().to_vec())
+            }.boxed()
+        }
+
+        fn reset(&self) -> BoxFuture<'_, ()> {
+            async { self.resets.fetch_add(1, Ordering::SeqCst); }.boxed()
+        }
+
+        fn establish(&self) -> BoxFuture<'_, Result<u64, RelayError>> {
+            async { Ok(self.init(&[]).await?.0) }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn port_death_resets_and_retries_once() {
+        let backend = DyingBackend { port_deaths: AtomicU32::new(1), ..Default::default() };
+
+        let data = generate_validation_data(&backend).await.unwrap();
+
+        assert_eq!(backend.resets.load(Ordering::SeqCst), 1);
+        // signed in the second session, not the one that died
+        assert_eq!(data, 1u64.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn port_death_is_only_retried_once() {
+        let backend = DyingBackend { port_deaths: AtomicU32::new(2), ..Default::default() };
+
+        let err = generate_validation_data(&backend).await.unwrap_err();
+
+        assert_eq!(err.nac_code(), Some(NacErrorCode::SendInvalidDest));
+        assert_eq!(backend.resets.load(Ordering::SeqCst), 1);
+        assert_eq!(backend.sessions.load(Ordering::SeqCst), 2);
+    }
+}
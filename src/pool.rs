@@ -0,0 +1,393 @@
+use std::{collections::{BTreeMap, BTreeSet}, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc, Mutex}, time::Duration};
+
+use futures::{future::BoxFuture, FutureExt};
+
+use crate::{error::RelayError, nac::NacBackend};
+
+// sessions are short lived; anything this far back has long been signed with
+const MAX_POOL_SESSIONS: usize = 1024;
+
+#[derive(Default)]
+struct MemberHealth {
+    consecutive_failures: u32,
+    ejected: bool,
+}
+
+struct PoolMember {
+    index: usize,
+    backend: Arc<dyn NacBackend>,
+    health: Mutex<MemberHealth>,
+}
+
+impl PoolMember {
+    fn is_healthy(&self) -> bool {
+        !self.health.lock().unwrap().ejected
+    }
+
+    // a member is only brought back by its probe, so this reports whether a probe needs starting
+    fn record<T>(&self, result: &Result<T, RelayError>, eject_after: u32) -> bool {
+        let mut health = self.health.lock().unwrap();
+        match result {
+            Ok(_) => {
+                health.consecutive_failures = 0;
+                false
+            },
+            Err(err) if matches!(err, RelayError::NacError(_) | RelayError::NacAgentUnavailable(_) | RelayError::NacAgentError(_)) => {
+                health.consecutive_failures += 1;
+                if !health.ejected && health.consecutive_failures >= eject_after {
+                    println!("Ejecting NAC pool member {} after {} failures ({err})", self.index, health.consecutive_failures);
+                    health.ejected = true;
+                    return true
+                }
+                false
+            },
+            // not the member's fault (e.g. bad input)
+            Err(_) => false,
+        }
+    }
+
+    // a whole session, the way the pool hands them out
+    async fn probe(&self) -> Result<(), RelayError> {
+        self.backend.reset().await;
+        self.backend.establish().await?;
+        Ok(())
+    }
+}
+
+/// Spreads NAC sessions across several backends, ejecting members that keep failing
+/// until a health probe succeeds again.
+pub struct NacPool {
+    members: Vec<Arc<PoolMember>>,
+    // pool ctx -> (member index, member ctx); member contexts can collide across devices
+    sessions: Mutex<BTreeMap<u64, (usize, u64)>>,
+    next_ctx: AtomicU64,
+    next_member: AtomicUsize,
+    eject_after: u32,
+    probe_interval: Duration,
+    // members whose connection went away since the last reset
+    lost: Mutex<BTreeSet<usize>>,
+}
+
+impl NacPool {
+    pub fn new(backends: Vec<Arc<dyn NacBackend>>, eject_after: u32, probe_interval: Duration) -> NacPool {
+        NacPool {
+            members: backends.into_iter().enumerate().map(|(index, backend)| Arc::new(PoolMember {
+                index,
+                backend,
+                health: Mutex::new(MemberHealth::default()),
+            })).collect(),
+            sessions: Mutex::new(BTreeMap::new()),
+            next_ctx: AtomicU64::new(0),
+            next_member: AtomicUsize::new(0),
+            eject_after: eject_after.max(1),
+            probe_interval,
+            lost: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    fn record<T>(&self, member: &Arc<PoolMember>, result: &Result<T, RelayError>) {
+        if result.as_ref().is_err_and(|err| err.is_backend_lost()) {
+            self.lost.lock().unwrap().insert(member.index);
+        }
+        if !member.record(result, self.eject_after) {
+            return
+        }
+        // the probe gives up once the pool (and so the member) is dropped
+        let weak_member = Arc::downgrade(member);
+        let probe_interval = self.probe_interval;
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(probe_interval).await;
+                let Some(member) = weak_member.upgrade() else { break };
+                match member.probe().await {
+                    Ok(()) => {
+                        println!("NAC pool member {} passed its health probe, bringing it back", member.index);
+                        *member.health.lock().unwrap() = MemberHealth::default();
+                        break
+                    },
+                    Err(err) => println!("NAC pool member {} still failing: {err}", member.index),
+                }
+            }
+        });
+    }
+
+    // once a member is ejected its sessions are gone for good; surface that as a lost backend so
+    // the session is redone on another member
+    fn finish_session_op<T>(&self, member: &Arc<PoolMember>, result: Result<T, RelayError>) -> Result<T, RelayError> {
+        self.record(member, &result);
+        match result {
+            Err(err) if !member.is_healthy() && !err.is_backend_lost() => {
+                self.lost.lock().unwrap().insert(member.index);
+                Err(RelayError::NacAgentUnavailable(format!("NAC pool member {} ejected: {err}", member.index)))
+            },
+            result => result,
+        }
+    }
+
+    // healthy members in round robin order, so sessions are spread across them
+    fn round_robin(&self) -> impl Iterator<Item = &Arc<PoolMember>> {
+        let start = self.next_member.fetch_add(1, Ordering::Relaxed);
+        (0..self.members.len())
+            .map(move |offset| &self.members[(start + offset) % self.members.len()])
+            .filter(|member| member.is_healthy())
+    }
+
+    fn add_session(&self, member: &PoolMember, member_ctx: u64) -> u64 {
+        let ctx = self.next_ctx.fetch_add(1, Ordering::Relaxed);
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.insert(ctx, (member.index, member_ctx));
+        while sessions.len() > MAX_POOL_SESSIONS {
+            sessions.pop_first();
+        }
+        ctx
+    }
+
+    fn session(&self, ctx: u64) -> Result<(Arc<PoolMember>, u64), RelayError> {
+        let Some((index, member_ctx)) = self.sessions.lock().unwrap().get(&ctx).copied() else {
+            return Err(RelayError::BadCommand(format!("unknown NAC pool session {ctx}")))
+        };
+        Ok((self.members[index].clone(), member_ctx))
+    }
+}
+
+impl NacBackend for NacPool {
+    fn init<'a>(&'a self, cert: &'a [u8]) -> BoxFuture<'a, Result<(u64, Vec<u8>), RelayError>> {
+        async move {
+            // fail over to the next member on error
+            let mut last_err = None;
+            for member in self.round_robin() {
+                let result = member.backend.init(cert).await;
+                self.record(member, &result);
+                match result {
+                    Ok((member_ctx, request)) => return Ok((self.add_session(member, member_ctx), request)),
+                    Err(err) => {
+                        println!("NAC pool member {} failed init: {err}", member.index);
+                        last_err = Some(err);
+                    }
+                }
+            }
+            Err(last_err.unwrap_or_else(|| RelayError::NacAgentUnavailable("no healthy NAC pool members".to_string())))
+        }.boxed()
+    }
+
+    fn key_establishment<'a>(&'a self, ctx: u64, response: &'a [u8]) -> BoxFuture<'a, Result<(), RelayError>> {
+        async move {
+            let (member, member_ctx) = self.session(ctx)?;
+            let result = member.backend.key_establishment(member_ctx, response).await;
+            self.finish_session_op(&member, result)
+        }.boxed()
+    }
+
+    fn sign<'a>(&'a self, ctx: u64, data: &'a [u8]) -> BoxFuture<'a, Result<Vec<u8>, RelayError>> {
+        async move {
+            let (member, member_ctx) = self.session(ctx)?;
+            let result = member.backend.sign(member_ctx, data).await;
+            self.finish_session_op(&member, result)
+        }.boxed()
+    }
+
+    // each member establishes its own way (fakes skip Apple's handshake), failing over like init
+    fn establish(&self) -> BoxFuture<'_, Result<u64, RelayError>> {
+        async move {
+            let mut last_err = None;
+            for member in self.round_robin() {
+                let result = member.backend.establish().await;
+                self.record(member, &result);
+                match result {
+                    Ok(member_ctx) => return Ok(self.add_session(member, member_ctx)),
+                    Err(err) => {
+                        println!("NAC pool member {} failed to establish a session: {err}", member.index);
+                        last_err = Some(err);
+                    }
+                }
+            }
+            Err(last_err.unwrap_or_else(|| RelayError::NacAgentUnavailable("no healthy NAC pool members".to_string())))
+        }.boxed()
+    }
+
+    // only the members that lost their connection; the others' sessions are still good
+    fn reset(&self) -> BoxFuture<'_, ()> {
+        async {
+            let lost = std::mem::take(&mut *self.lost.lock().unwrap());
+            for index in lost {
+                self.members[index].backend.reset().await;
+            }
+        }.boxed()
+    }
+
+    // one fake member is enough to hand out fake data
+    fn is_fake(&self) -> bool {
+        self.members.iter().any(|member| member.backend.is_fake())
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use std::{sync::{atomic::{AtomicBool, AtomicU32, Ordering}, Arc}, time::Duration};
+
+    use futures::{future::BoxFuture, FutureExt};
+
+    use crate::{error::{NacErrorCode, RelayError}, nac::{FakeNacBackend, NacBackend}};
+
+    use super::NacPool;
+
+    const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// A fake member that fails everything with a dead absd port while `broken` is set
+    #[derive(Default)]
+    struct FlakyMember {
+        fake: FakeNacBackend,
+        broken: AtomicBool,
+        inits: AtomicU32,
+        resets: AtomicU32,
+    }
+
+    impl FlakyMember {
+        fn check(&self) -> Result<(), RelayError> {
+            if self.broken.load(Ordering::SeqCst) {
+                return Err(RelayError::NacError(NacErrorCode::RcvPortDied))
+            }
+            Ok(())
+        }
+    }
+
+    impl NacBackend for FlakyMember {
+        fn init<'a>(&'a self, cert: &'a [u8]) -> BoxFuture<'a, Result<(u64, Vec<u8>), RelayError>> {
+            async move {
+                self.inits.fetch_add(1, Ordering::SeqCst);
+                self.check()?;
+                self.fake.init(cert).await
+            }.boxed()
+        }
+
+        fn key_establishment<'a>(&'a self, ctx: u64, response: &'a [u8]) -> BoxFuture<'a, Result<(), RelayError>> {
+            async move {
+                self.check()?;
+                self.fake.key_establishment(ctx, response).await
+            }.boxed()
+        }
+
+        fn sign<'a>(&'a self, ctx: u64, data: &'a [u8]) -> BoxFuture<'a, Result<Vec<u8>, RelayError>> {
+            async move {
+                self.check()?;
+                self.fake.sign(ctx, data).await
+            }.boxed()
+        }
+
+        fn reset(&self) -> BoxFuture<'_, ()> {
+            async { self.resets.fetch_add(1, Ordering::SeqCst); }.boxed()
+        }
+
+        fn establish(&self) -> BoxFuture<'_, Result<u64, RelayError>> {
+            async {
+                self.inits.fetch_add(1, Ordering::SeqCst);
+                self.check()?;
+                self.fake.establish().await
+            }.boxed()
+        }
+
+        fn is_fake(&self) -> bool {
+            true
+        }
+    }
+
+    fn pool(members: &[&Arc<FlakyMember>], eject_after: u32) -> NacPool {
+        NacPool::new(members.iter().map(|member| Arc::clone(member) as Arc<dyn NacBackend>).collect(), eject_after, PROBE_INTERVAL)
+    }
+
+    fn inits(member: &FlakyMember) -> u32 {
+        member.inits.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_over_and_ejects_a_failing_member() {
+        let broken = Arc::new(FlakyMember { broken: AtomicBool::new(true), ..Default::default() });
+        let healthy = Arc::new(FlakyMember::default());
+        let pool = pool(&[&broken, &healthy], 2);
+
+        for _ in 0..4 {
+            pool.init(b"cert").await.unwrap();
+        }
+
+        // tried on the first two rounds, then skipped once ejected
+        assert_eq!(inits(&broken), 2);
+        assert_eq!(inits(&healthy), 4);
+        assert!(!pool.members[0].is_healthy());
+        assert!(pool.members[1].is_healthy());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn probe_brings_a_member_back() {
+        let flaky = Arc::new(FlakyMember { broken: AtomicBool::new(true), ..Default::default() });
+        let healthy = Arc::new(FlakyMember::default());
+        let pool = pool(&[&flaky, &healthy], 1);
+        pool.init(b"cert").await.unwrap();
+        assert!(!pool.members[0].is_healthy());
+
+        // still broken, so the first probe keeps it out
+        tokio::time::sleep(PROBE_INTERVAL + Duration::from_secs(1)).await;
+        assert_eq!(inits(&flaky), 2);
+        assert!(!pool.members[0].is_healthy());
+
+        flaky.broken.store(false, Ordering::SeqCst);
+        tokio::time::sleep(PROBE_INTERVAL).await;
+        assert_eq!(inits(&flaky), 3);
+        assert!(pool.members[0].is_healthy());
+        assert_eq!(flaky.resets.load(Ordering::SeqCst), 2);
+
+        pool.init(b"cert").await.unwrap();
+        pool.init(b"cert").await.unwrap();
+        assert_eq!(inits(&flaky), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn probe_stops_with_the_pool() {
+        let broken = Arc::new(FlakyMember { broken: AtomicBool::new(true), ..Default::default() });
+        let healthy = Arc::new(FlakyMember::default());
+        let pool = pool(&[&broken, &healthy], 1);
+        pool.init(b"cert").await.unwrap();
+        tokio::time::sleep(PROBE_INTERVAL + Duration::from_secs(1)).await;
+        assert_eq!(inits(&broken), 2);
+
+        drop(pool);
+        tokio::time::sleep(10 * PROBE_INTERVAL).await;
+        assert_eq!(inits(&broken), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn establishes_through_the_members() {
+        let pool = NacPool::new(vec![Arc::new(FakeNacBackend::default()), Arc::new(FakeNacBackend::default())], 3, PROBE_INTERVAL);
+        assert!(pool.is_fake());
+        // no Apple handshake for fake members
+        let ctx = pool.establish().await.unwrap();
+        assert!(pool.sign(ctx, b"data").await.unwrap().starts_with(b"FAKENAC"));
+
+        let broken = Arc::new(FlakyMember { broken: AtomicBool::new(true), ..Default::default() });
+        let healthy = Arc::new(FlakyMember::default());
+        let pool = self::pool(&[&broken, &healthy], 3);
+        let ctx = pool.establish().await.unwrap();
+        assert!(pool.sign(ctx, b"data").await.is_ok());
+        assert_eq!((inits(&broken), inits(&healthy)), (1, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reset_only_touches_the_lost_member() {
+        let dying = Arc::new(FlakyMember::default());
+        let healthy = Arc::new(FlakyMember::default());
+        let pool = pool(&[&dying, &healthy], 3);
+        let (dying_ctx, _) = pool.init(b"cert").await.unwrap();
+        let (healthy_ctx, _) = pool.init(b"cert").await.unwrap();
+
+        dying.broken.store(true, Ordering::SeqCst);
+        let err = pool.sign(dying_ctx, b"data").await.unwrap_err();
+        assert!(err.is_backend_lost());
+        pool.reset().await;
+
+        assert_eq!(dying.resets.load(Ordering::SeqCst), 1);
+        assert_eq!(healthy.resets.load(Ordering::SeqCst), 0);
+        assert!(pool.sign(healthy_ctx, b"data").await.is_ok());
+        // already reset; nothing new was lost
+        pool.reset().await;
+        assert_eq!(dying.resets.load(Ordering::SeqCst), 1);
+    }
+}
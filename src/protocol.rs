@@ -0,0 +1,108 @@
+//! Wire types for the registration relay websocket protocol, shared by providers, the relay
+//! server and clients.
+
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::Message;
+
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RelayState {
+    pub code: String,
+    pub secret: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RelayVersions {
+    pub hardware_version: String,
+    pub software_name: String,
+    pub software_version: String,
+    pub software_build_id: String,
+    pub unique_device_id: String,
+    pub serial_number: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum CommandData {
+    Code {
+        #[serde(flatten)]
+        code: RelayState
+    },
+    Versions {
+        versions: RelayVersions
+    },
+    ValidationData {
+        data: String,
+    },
+    Payload {
+        payload: String,
+    },
+    Signature {
+        signature: String,
+    },
+    Error {
+        error: String,
+        nac_code: Option<i32>,
+        transient: bool,
+    },
+    SelfTest {
+        self_test: SelfTestReport,
+    },
+    Empty {},
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RelayCommand {
+    pub command: String,
+    pub id: Option<u64>,
+    pub data: Option<CommandData>,
+}
+
+impl RelayCommand {
+    pub fn into_message(self) -> Message {
+        Message::Text(serde_json::to_string(&self).unwrap())
+    }
+
+    pub fn respond(&self, data: CommandData) -> Message {
+        RelayCommand {
+            command: "response".to_string(),
+            id: Some(self.id.unwrap()),
+            data: Some(data)
+        }.into_message()
+    }
+}
+
+/// Whether a relay knows a code and has its provider connected
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ProviderStatus {
+    pub code: String,
+    pub online: bool,
+    /// Online and not failing its self-test; relays that predate this only say `online`
+    #[serde(default = "ProviderStatus::default_ready")]
+    pub ready: bool,
+    /// What the connected provider reported from its latest self-test, if it runs one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub self_test: Option<SelfTestReport>,
+}
+
+impl ProviderStatus {
+    fn default_ready() -> bool {
+        true
+    }
+}
+
+/// A provider's check that it can serve validation requests, sent to the relay as `self-test`
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SelfTestReport {
+    pub passed: bool,
+    pub at_ms: u64,
+    pub checks: Vec<SelfTestCheck>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SelfTestCheck {
+    pub name: String,
+    pub passed: bool,
+    pub error: Option<String>,
+    pub latency_ms: u64,
+}
@@ -0,0 +1,256 @@
+//! Embedding a relay provider in another service: pick where the registration is kept, which
+//! NAC backend signs and where version info comes from, then start it and listen for events.
+
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast;
+
+use crate::{commands::CommandHandler, crash::CrashReportConfig, device::{DeviceInfoConfig, DeviceInfoProvider}, error::RelayError, events::RelayEvent, hooks::{spawn_hooks, HooksConfig}, local_api::{LocalApiConfig, LocalApiResource}, nac::{CertCacheResource, NacBackend, NacConfig, NacSessionResource}, protocol::RelayState, qr::{spawn_terminal_qr, QrConfig}, relay::{Relay, RelayResource}, self_test::SelfTest, supervisor::{Supervisor, SupervisorHealth}, util::ResourceManagerBuilder};
+
+pub const DEFAULT_RELAY_URL: &str = "wss://registration-relay.beeper.com/api/v1/provider";
+
+/// Keeps the code and secret the relay registered us with, so restarts keep the same code
+pub trait StateStore: Send + Sync {
+    fn load(&self) -> Option<RelayState>;
+    fn save(&self, state: &RelayState) -> Result<(), RelayError>;
+}
+
+/// Forgets the registration when the process exits
+#[derive(Default)]
+pub struct MemoryStateStore {
+    state: Mutex<Option<RelayState>>,
+}
+
+impl MemoryStateStore {
+    pub fn new(state: Option<RelayState>) -> MemoryStateStore {
+        MemoryStateStore { state: Mutex::new(state) }
+    }
+}
+
+impl StateStore for MemoryStateStore {
+    fn load(&self) -> Option<RelayState> {
+        self.state.lock().unwrap().clone()
+    }
+
+    fn save(&self, state: &RelayState) -> Result<(), RelayError> {
+        *self.state.lock().unwrap() = Some(state.clone());
+        Ok(())
+    }
+}
+
+pub struct ProviderBuilder {
+    url: String,
+    state_store: Option<Arc<dyn StateStore>>,
+    nac: Option<Arc<dyn NacBackend>>,
+    device_info: Option<Arc<dyn DeviceInfoProvider>>,
+    hooks: HooksConfig,
+    qr: Option<QrConfig>,
+    local_api: Option<LocalApiConfig>,
+    crash_reports: Option<CrashReportConfig>,
+    self_test: bool,
+    allow_fake_nac: bool,
+}
+
+impl Default for ProviderBuilder {
+    fn default() -> Self {
+        ProviderBuilder::new()
+    }
+}
+
+impl ProviderBuilder {
+    pub fn new() -> ProviderBuilder {
+        ProviderBuilder {
+            url: DEFAULT_RELAY_URL.to_string(),
+            state_store: None,
+            nac: None,
+            device_info: None,
+            hooks: HooksConfig::default(),
+            qr: None,
+            local_api: None,
+            crash_reports: None,
+            self_test: false,
+            allow_fake_nac: false,
+        }
+    }
+
+    /// The relay's provider websocket, e.g. `wss://registration-relay.beeper.com/api/v1/provider`
+    pub fn url(mut self, url: impl Into<String>) -> ProviderBuilder {
+        self.url = url.into();
+        self
+    }
+
+    /// Defaults to a `MemoryStateStore`
+    pub fn state_store(mut self, state_store: Arc<dyn StateStore>) -> ProviderBuilder {
+        self.state_store = Some(state_store);
+        self
+    }
+
+    /// Defaults to the backend `NacConfig::default()` builds
+    pub fn nac_backend(mut self, nac: Arc<dyn NacBackend>) -> ProviderBuilder {
+        self.nac = Some(nac);
+        self
+    }
+
+    /// Defaults to the provider `DeviceInfoConfig::default()` builds
+    pub fn device_info(mut self, device_info: Arc<dyn DeviceInfoProvider>) -> ProviderBuilder {
+        self.device_info = Some(device_info);
+        self
+    }
+
+    /// Commands and webhooks to run on relay events
+    pub fn hooks(mut self, hooks: HooksConfig) -> ProviderBuilder {
+        self.hooks = hooks;
+        self
+    }
+
+    /// How the pairing QR is shown; off unless set
+    pub fn qr(mut self, qr: QrConfig) -> ProviderBuilder {
+        self.qr = Some(qr);
+        self
+    }
+
+    /// Serve the local HTTP API alongside the relay connection
+    pub fn local_api(mut self, local_api: LocalApiConfig) -> ProviderBuilder {
+        self.local_api = Some(local_api);
+        self
+    }
+
+    /// Write crash reports for panics in the relay connection; off unless set
+    pub fn crash_reports(mut self, crash_reports: CrashReportConfig) -> ProviderBuilder {
+        self.crash_reports = Some(crash_reports);
+        self
+    }
+
+    /// Check that validation data can be generated before registering and every few minutes after.
+    /// A self-hosted relay reports the provider as not ready while it can't; the public relay
+    /// doesn't know about readiness, so there the provider stays unregistered instead
+    pub fn self_test(mut self, self_test: bool) -> ProviderBuilder {
+        self.self_test = self_test;
+        self
+    }
+
+    /// Serve a backend whose signatures are made up, for testing against a self-hosted relay; off
+    /// unless set, and never allowed on the public relay
+    pub fn allow_fake_nac(mut self, allow_fake_nac: bool) -> ProviderBuilder {
+        self.allow_fake_nac = allow_fake_nac;
+        self
+    }
+
+    /// Connect to the relay in the background, reconnecting with backoff until stopped
+    pub async fn start(self) -> Result<RelayProvider, RelayError> {
+        let device_info = match self.device_info {
+            Some(device_info) => device_info,
+            None => DeviceInfoConfig::default().build()?,
+        };
+        let nac = self.nac.unwrap_or_else(|| NacConfig::default().build());
+        if nac.is_fake() && !self.allow_fake_nac {
+            return Err(RelayError::BadCommand("refusing to serve the fake NAC backend's data without allow_fake_nac".to_string()))
+        }
+        if nac.is_fake() && !is_self_hosted(&self.url) {
+            return Err(RelayError::BadCommand("refusing to serve the fake NAC backend's data on the public relay; set a self-hosted relay url".to_string()))
+        }
+        let state_store = self.state_store.unwrap_or_else(|| Arc::new(MemoryStateStore::default()));
+        let (events, _) = broadcast::channel(99);
+        // subscribe before the relay starts so the first events aren't missed
+        if !self.hooks.hooks.is_empty() {
+            spawn_hooks(self.hooks, events.subscribe());
+        }
+        let qr = self.qr.unwrap_or(QrConfig { terminal: false, deep_link: None });
+        if qr.terminal {
+            spawn_terminal_qr(qr.clone(), events.subscribe());
+        }
+        let handler = Arc::new(CommandHandler::new(nac.clone(), device_info, events));
+
+        let crash_reports = self.crash_reports;
+        let with_crash_reports = |builder: ResourceManagerBuilder<_>| match crash_reports.clone() {
+            Some(crash_reports) => builder.crash_reports(crash_reports),
+            None => builder,
+        };
+        let mut supervisor = Supervisor::new();
+        // the fake backend never talks to Apple
+        let session_depends_on: &[&str] = if nac.is_fake() { &[] } else {
+            supervisor.add("apple-cert", &[], with_crash_reports(CertCacheResource::manager_builder()), Arc::new(CertCacheResource))?;
+            &["apple-cert"]
+        };
+        supervisor.add("nac-session", session_depends_on, with_crash_reports(NacSessionResource::manager_builder()), Arc::new(NacSessionResource::new(nac)))?;
+        let self_test = if self.self_test {
+            let self_test = Arc::new(SelfTest::new(handler.clone()));
+            supervisor.add("self-test", &[], with_crash_reports(SelfTest::manager_builder()), self_test.clone())?;
+            Some(self_test)
+        } else {
+            None
+        };
+        // our own relay server is told about failures instead, so only the public one waits for the self-test to pass
+        let relay_depends_on: &[&str] = if self_test.is_some() && !is_self_hosted(&self.url) { &["nac-session", "self-test"] } else { &["nac-session"] };
+        let relay = supervisor.add("relay", relay_depends_on, with_crash_reports(RelayResource::manager_builder()),
+            Arc::new(RelayResource::new(self.url, state_store, handler.clone(), self_test)))?;
+        if let Some(config) = self.local_api {
+            supervisor.add("local-api", &[], with_crash_reports(LocalApiResource::manager_builder()),
+                Arc::new(LocalApiResource::new(config, handler, Some(relay.clone()), qr)))?;
+        }
+        Ok(RelayProvider { relay, supervisor })
+    }
+}
+
+// anything that doesn't parse, or points at the public relay however it's spelled, counts as public
+pub(crate) fn is_self_hosted(url: &str) -> bool {
+    let host = |url: &str| reqwest::Url::parse(url).ok()?.host_str().map(|host| host.trim_end_matches('.').to_string());
+    host(url).is_some_and(|ours| Some(ours) != host(DEFAULT_RELAY_URL))
+}
+
+/// A running relay provider
+pub struct RelayProvider {
+    relay: Relay,
+    // the relay connection and any servers running next to it
+    supervisor: Supervisor,
+}
+
+impl RelayProvider {
+    pub fn builder() -> ProviderBuilder {
+        ProviderBuilder::new()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<RelayEvent> {
+        self.relay.events.subscribe()
+    }
+
+    /// The current registration, once the relay has given us one
+    pub async fn state(&self) -> Option<RelayState> {
+        self.relay.state.lock().await.clone()
+    }
+
+    pub fn relay(&self) -> &Relay {
+        &self.relay
+    }
+
+    /// How the relay connection and local servers are doing, together
+    pub fn health(&self) -> SupervisorHealth {
+        self.supervisor.health()
+    }
+
+    /// Disconnect from the relay and stop reconnecting
+    pub async fn stop(self) {
+        self.supervisor.shutdown().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_self_hosted;
+
+    #[test]
+    fn public_relay_is_recognised_however_it_is_written() {
+        for url in [
+            "wss://registration-relay.beeper.com/api/v1/provider",
+            "wss://registration-relay.beeper.com/api/v1/provider/",
+            "WSS://Registration-Relay.Beeper.COM/api/v1/provider",
+            "wss://registration-relay.beeper.com./api/v1/provider?x=1",
+            "wss://registration-relay.beeper.com:443/api/v1/provider",
+            "not a url",
+        ] {
+            assert!(!is_self_hosted(url), "{url}");
+        }
+        assert!(is_self_hosted("ws://127.0.0.1:8080/api/v1/provider"));
+        assert!(is_self_hosted("wss://relay.example.com/api/v1/provider"));
+    }
+}
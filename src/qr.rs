@@ -0,0 +1,147 @@
+//! QR codes for the pairing code, so it can be scanned instead of typed into the client.
+
+use qrcode::{render::unicode::Dense1x2, Color, QrCode};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::broadcast, task::JoinHandle};
+
+use crate::{error::RelayError, events::RelayEvent};
+
+// modules of white border the QR spec asks for around the code
+const QUIET_ZONE: usize = 4;
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct QrConfig {
+    /// Print the QR to the terminal when the relay hands out a new code
+    #[serde(default = "QrConfig::default_terminal")]
+    pub terminal: bool,
+    /// Encode this instead of the bare code, with `{code}` replaced, e.g. `openbubbles://pair?code={code}`
+    #[serde(default)]
+    pub deep_link: Option<String>,
+}
+
+impl Default for QrConfig {
+    fn default() -> Self {
+        QrConfig {
+            terminal: QrConfig::default_terminal(),
+            deep_link: None,
+        }
+    }
+}
+
+impl QrConfig {
+    fn default_terminal() -> bool {
+        true
+    }
+
+    /// What the QR for `code` encodes
+    pub fn contents(&self, code: &str) -> String {
+        match &self.deep_link {
+            Some(template) => template.replace("{code}", code),
+            None => code.to_string(),
+        }
+    }
+}
+
+pub fn render_terminal(contents: &str) -> Result<String, RelayError> {
+    let code = QrCode::new(contents)?;
+    // terminals are usually light on dark, so swap the colors to get dark on light
+    Ok(code.render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build())
+}
+
+/// A grayscale PNG with `scale` pixels per module
+pub fn render_png(contents: &str, scale: usize) -> Result<Vec<u8>, RelayError> {
+    let code = QrCode::new(contents)?;
+    let modules = code.width();
+    let colors = code.to_colors();
+    let size = (modules + QUIET_ZONE * 2) * scale;
+
+    let mut pixels = vec![0xffu8; size * size];
+    for (index, color) in colors.iter().enumerate() {
+        if *color != Color::Dark {
+            continue
+        }
+        let x = (index % modules + QUIET_ZONE) * scale;
+        let y = (index / modules + QUIET_ZONE) * scale;
+        for row in y..y + scale {
+            pixels[row * size + x..row * size + x + scale].fill(0);
+        }
+    }
+
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+    Ok(png)
+}
+
+/// Print the pairing QR whenever the relay registers us under a code we didn't have before
+pub fn spawn_terminal_qr(config: QrConfig, mut events: broadcast::Receiver<RelayEvent>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(RelayEvent::Registered { code, is_new: true }) => {
+                    let contents = config.contents(&code);
+                    match render_terminal(&contents) {
+                        Ok(qr) => println!("Scan to pair ({contents}):\n{qr}"),
+                        Err(err) => println!("Failed to render pairing QR: {err}"),
+                    }
+                },
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use qrcode::QrCode;
+
+    use super::{render_png, render_terminal, QrConfig, QUIET_ZONE};
+
+    const CODE: &str = "ABCD-1234";
+
+    #[test]
+    fn png_is_the_code_with_its_quiet_zone() {
+        let modules = QrCode::new(CODE).unwrap().width();
+        let png = render_png(CODE, 4).unwrap();
+
+        let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+        let info = reader.info();
+        let size = (modules + QUIET_ZONE * 2) * 4;
+        assert_eq!((info.width as usize, info.height as usize), (size, size));
+        assert_eq!((info.color_type, info.bit_depth), (png::ColorType::Grayscale, png::BitDepth::Eight));
+
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        // the quiet zone is white, and the finder pattern starts dark right after it
+        assert_eq!(pixels[0], 0xff);
+        let corner = QUIET_ZONE * 4;
+        assert_eq!(pixels[corner * size + corner], 0);
+    }
+
+    #[test]
+    fn terminal_qr_is_square() {
+        let modules = QrCode::new(CODE).unwrap().width() + QUIET_ZONE * 2;
+        let qr = render_terminal(CODE).unwrap();
+
+        // two modules per line, so the last line is half empty when the width is odd
+        let lines: Vec<_> = qr.lines().collect();
+        assert_eq!(lines.len(), modules.div_ceil(2));
+        assert!(lines.iter().all(|line| line.chars().count() == modules), "{qr}");
+        assert!(qr.chars().any(|c| c != ' ' && c != '\n'));
+    }
+
+    #[test]
+    fn deep_links_wrap_the_code() {
+        let config = QrConfig { deep_link: Some("openbubbles://pair?code={code}".to_string()), ..Default::default() };
+        assert_eq!(config.contents(CODE), "openbubbles://pair?code=ABCD-1234");
+        assert_eq!(QrConfig::default().contents(CODE), CODE);
+    }
+}
@@ -1,110 +1,117 @@
-use std::{sync::Arc, time::{Duration, SystemTime}};
+use std::{sync::Arc, time::Duration};
 
 use backon::ExponentialBuilder;
 use futures::{SinkExt, StreamExt};
-use nix::sys::utsname::uname;
-use serde::{Deserialize, Serialize};
-use tokio::{net::TcpStream, select, sync::Mutex, task::JoinHandle, time::{self, Instant}};
+use tokio::{net::TcpStream, select, sync::{broadcast, watch, Mutex}, task::JoinHandle, time::{self, Instant}};
 use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
 
-use crate::{base64_encode, c::mg_copy_answer_rs, error::RelayError, nac::generate_validation_data, util::{Resource, ResourceManager}};
+use crate::{commands::CommandHandler, error::RelayError, events::RelayEvent, protocol::{CommandData, RelayCommand, RelayState, SelfTestReport}, provider::{is_self_hosted, StateStore}, self_test::{timed_out, SelfTest}, util::{ManagerError, Resource, ResourceManager, ResourceManagerBuilder}};
 
-
-#[derive(Deserialize, Serialize, Clone)]
-pub struct RelayState {
-    pub code: String,
-    pub secret: String,
-}
+const PING_INTERVAL: Duration = Duration::from_secs(60);
+// two missed pongs; anything quieter is a half-open connection
+const PONG_TIMEOUT: Duration = Duration::from_secs(150);
+// as long as the self-test gets for a health check
+const SELF_TEST_WAIT: Duration = Duration::from_secs(60);
 
 pub struct RelayResource {
     pub url: Mutex<String>,
     pub state: Mutex<Option<RelayState>>,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct RelayVersions {
-    hardware_version: String,
-    software_name: String,
-    software_version: String,
-    software_build_id: String,
-    unique_device_id: String,
-    serial_number: String,
-}
-
-#[derive(Deserialize, Serialize)]
-#[serde(untagged)]
-enum CommandData {
-    Code {
-        #[serde(flatten)]
-        code: RelayState
-    },
-    Versions {
-        versions: RelayVersions
-    },
-    ValidationData {
-        data: String,
-    },
-    Empty {},
-}
-
-#[derive(Deserialize, Serialize)]
-struct RelayCommand {
-    command: String,
-    id: Option<u64>,
-    data: Option<CommandData>,
-}
-
-impl RelayCommand {
-    fn to_message(self) -> Message {
-        Message::Text(serde_json::to_string(&self).unwrap())
-    }
-
-    fn respond(&self, data: CommandData) -> Message {
-        RelayCommand {
-            command: "response".to_string(),
-            id: Some(self.id.unwrap()),
-            data: Some(data)
-        }.to_message()
-    }
+    pub handler: Arc<CommandHandler>,
+    pub state_store: Arc<dyn StateStore>,
+    pub events: broadcast::Sender<RelayEvent>,
+    /// Run before registering, then reported to the relay on every registration and every rerun
+    pub self_test: Option<Arc<SelfTest>>,
+    // how long registering waits for the self-test's first result
+    self_test_wait: Duration,
+    last_pong: std::sync::Mutex<Instant>,
 }
 
 pub type Relay = Arc<ResourceManager<RelayResource>>;
 
 impl Resource for RelayResource {
+    type Error = RelayError;
+
     async fn generate(self: &Arc<Self>) -> Result<JoinHandle<()>, RelayError> {
-        let (mut ws_stream, _) = connect_async(&*self.url.lock().await).await?;
+        let url = self.url.lock().await.clone();
+        // the relay should hear how the self-test went as soon as we register, pass or fail; only
+        // our own relay server knows the message, the public one is kept behind the self-test instead
+        let mut reports = self.self_test.as_ref().filter(|_| is_self_hosted(&url)).map(|self_test| self_test.subscribe());
+        let mut first_report = None;
+        if let Some(reports) = &mut reports {
+            if time::timeout(self.self_test_wait, reports.wait_for(Option::is_some)).await.is_err() {
+                println!("No self-test result after {:?}, registering as not ready", self.self_test_wait);
+                first_report = Some(timed_out(self.self_test_wait));
+            }
+        }
+
+        let _ = self.events.send(RelayEvent::Connecting { url: url.clone() });
+        let (mut ws_stream, _) = connect_async(&url).await?;
 
         let mut state = self.state.lock().await;
         
         let mapped = state.clone().map(|i| CommandData::Code { code: i }).unwrap_or(CommandData::Empty {  });
 
-        ws_stream.send(RelayCommand { id: None, command: "register".to_string(), data: Some(mapped)}.to_message()).await?;
+        ws_stream.send(RelayCommand { id: None, command: "register".to_string(), data: Some(mapped)}.into_message()).await?;
 
         let item: RelayCommand = serde_json::from_str(&ws_stream.next().await.unwrap()?.into_text()?)?;
         let Some(CommandData::Code { code }) = item.data else { panic!("bad response!") };
 
         println!("Connected with code {}", code.code);
 
+        let is_new = state.as_ref().is_none_or(|old| old.code != code.code);
+        if let Err(err) = self.state_store.save(&code) {
+            println!("Failed to save relay state: {err}");
+        }
+        if let Some(report) = first_report.or_else(|| reports.as_mut().and_then(|reports| reports.borrow_and_update().clone())) {
+            ws_stream.send(self_test_message(report)).await?;
+        }
+        let _ = self.events.send(RelayEvent::Registered { code: code.code.clone(), is_new });
         *state = Some(code);
+        *self.last_pong.lock().unwrap() = Instant::now();
 
-
+        let resource = self.clone();
         Ok(tokio::spawn(async move {
-            match RelayResource::poll(ws_stream).await {
-                Ok(_) => {},
+            let reason = match resource.poll(ws_stream, reports).await {
+                Ok(_) => "connection closed".to_string(),
                 Err(err) => {
                     println!("error {err}");
+                    err.to_string()
                 }
-            }
+            };
+            let _ = resource.events.send(RelayEvent::Disconnected { reason });
         }))
     }
+
+    fn retry_scheduled(&self, error: &ManagerError<RelayError>, retry_in: Duration) {
+        let _ = self.events.send(RelayEvent::RetryScheduled { error: error.to_string(), r#in: retry_in });
+    }
+
+    fn flapping(&self, unstable_runs: u32, retry_in: Duration) {
+        let _ = self.events.send(RelayEvent::Flapping { unstable_runs, r#in: retry_in });
+    }
+
+    async fn health_check(self: &Arc<Self>) -> Result<(), RelayError> {
+        let since_pong = self.last_pong.lock().unwrap().elapsed();
+        if since_pong > PONG_TIMEOUT {
+            return Err(RelayError::RelayUnresponsive(since_pong))
+        }
+        Ok(())
+    }
+
+    // the poll task is aborted rather than exiting, so it won't report this itself
+    fn aborted(&self, reason: &str) {
+        let _ = self.events.send(RelayEvent::Disconnected { reason: reason.to_string() });
+    }
 }
 
 impl RelayResource {
-    async fn poll(mut ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>) -> Result<(), RelayError> {
-        let ping_interval = Duration::from_secs(60);
+    async fn poll(&self, mut ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>, mut reports: Option<watch::Receiver<Option<SelfTestReport>>>) -> Result<(), RelayError> {
         let mut last_ping = Instant::now();
         loop {
             select! {
+                Some(report) = next_report(&mut reports) => {
+                    ws_stream.send(self_test_message(report)).await?;
+                },
                 msg = ws_stream.next() => {
                     let Some(msg) = msg else { continue };
                     let msg = match msg {
@@ -116,33 +123,21 @@ impl RelayResource {
                     };
                     
                     let command: RelayCommand = serde_json::from_str(&msg).unwrap();
-                    match command.command.as_str() {
-                        "get-version-info" => {
-                            let uts = uname().unwrap();
-                            ws_stream.send(command.respond(CommandData::Versions { versions: RelayVersions {
-                                hardware_version: uts.machine().to_str().unwrap().to_string(),
-                                software_name: "iPhone OS".to_string(),
-                                software_version: mg_copy_answer_rs("ProductVersion"),
-                                software_build_id: mg_copy_answer_rs("BuildVersion"),
-                                unique_device_id: mg_copy_answer_rs("UniqueDeviceID"),
-                                serial_number: mg_copy_answer_rs("SerialNumber"),
-                            } })).await?;
-                        },
-                        "get-validation-data" => {
-                            println!("Generating validation data!");
-                            ws_stream.send(command.respond(CommandData::ValidationData { data: base64_encode(&generate_validation_data().await?) })).await?;
-                            println!("Sent validation data!");
-                        },
-                        "pong" => {},
-                        _raw => panic!("bad command {_raw}"),
+                    if command.command == "pong" {
+                        *self.last_pong.lock().unwrap() = Instant::now();
+                        continue
                     }
+                    let Some(response) = self.handler.handle(&command).await else {
+                        panic!("bad command {}", command.command)
+                    };
+                    ws_stream.send(command.respond(response)).await?;
                 },
-                _ = time::sleep_until(last_ping + ping_interval) => {
+                _ = time::sleep_until(last_ping + PING_INTERVAL) => {
                     ws_stream.send(RelayCommand {
                         command: "ping".to_string(),
                         id: None,
                         data: None,
-                    }.to_message()).await?;
+                    }.into_message()).await?;
                     last_ping = Instant::now();
                 }
             }
@@ -150,16 +145,137 @@ impl RelayResource {
         Ok(())
     }
 
-    pub fn new(url: String, state: Option<RelayState>) -> Relay {
-        let resource = RelayResource {
+    pub fn new(url: String, state_store: Arc<dyn StateStore>, handler: Arc<CommandHandler>, self_test: Option<Arc<SelfTest>>) -> RelayResource {
+        RelayResource {
             url: Mutex::new(url),
-            state: Mutex::new(state),
-        };
+            state: Mutex::new(state_store.load()),
+            events: handler.events.clone(),
+            handler,
+            state_store,
+            self_test,
+            self_test_wait: SELF_TEST_WAIT,
+            last_pong: std::sync::Mutex::new(Instant::now()),
+        }
+    }
 
-        ResourceManager::new(
-            Arc::new(resource),
-            ExponentialBuilder::default()
+    /// How the relay connection is retried and checked on
+    pub fn manager_builder() -> ResourceManagerBuilder<ExponentialBuilder> {
+        ResourceManagerBuilder::new()
+            .backoff(ExponentialBuilder::default()
                 .with_max_delay(Duration::from_secs(30))
-                .with_max_times(usize::MAX), None)
+                .with_max_times(usize::MAX))
+            // keep providers from reconnecting in lockstep after a relay outage
+            .jitter(true)
+            .health_interval(Duration::from_secs(30))
+    }
+}
+
+fn self_test_message(report: SelfTestReport) -> Message {
+    RelayCommand { id: None, command: "self-test".to_string(), data: Some(CommandData::SelfTest { self_test: report }) }.into_message()
+}
+
+// the next self-test result; never resolves without a self-test
+async fn next_report(reports: &mut Option<watch::Receiver<Option<SelfTestReport>>>) -> Option<SelfTestReport> {
+    let Some(reports) = reports else { return std::future::pending().await };
+    if reports.changed().await.is_err() {
+        return std::future::pending().await
+    }
+    reports.borrow_and_update().clone()
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use backon::ConstantBuilder;
+    use tokio::{net::TcpListener, sync::broadcast};
+
+    use crate::{events::RelayEvent, provider::MemoryStateStore, self_test::SelfTest, testing::{fake_handler, fixture_device_info, spawn_relay_server, TempDir}};
+
+    use super::RelayResource;
+
+    async fn next(events: &mut broadcast::Receiver<RelayEvent>) -> RelayEvent {
+        tokio::time::timeout(Duration::from_secs(10), events.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn reports_connecting_registering_disconnecting_and_retrying() {
+        let dir = TempDir::new("relay-events");
+        let (_server, base_url) = spawn_relay_server(&dir).await;
+        let url = format!("{}/api/v1/provider", base_url.replacen("http://", "ws://", 1));
+        let handler = fake_handler(fixture_device_info());
+        let mut events = handler.events.subscribe();
+        let relay = RelayResource::manager_builder()
+            .backoff(ConstantBuilder::default().with_delay(Duration::from_millis(100)).with_max_times(usize::MAX))
+            .jitter(false)
+            .build(Arc::new(RelayResource::new(url.clone(), Arc::new(MemoryStateStore::default()), handler, None)));
+
+        assert!(matches!(next(&mut events).await, RelayEvent::Connecting { url: connecting } if connecting == url));
+        let RelayEvent::Registered { code, is_new: true } = next(&mut events).await else { panic!("not registered") };
+
+        // point it somewhere nothing listens, so the reconnect fails
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        *relay.url.lock().await = format!("ws://{dead}/api/v1/provider");
+        relay.request_update().await;
+        assert!(matches!(next(&mut events).await, RelayEvent::Disconnected { reason } if reason == "regenerating"));
+        assert!(matches!(next(&mut events).await, RelayEvent::Connecting { .. }));
+        assert!(matches!(next(&mut events).await, RelayEvent::RetryScheduled { r#in, .. } if r#in == Duration::from_millis(100)));
+
+        *relay.url.lock().await = url;
+        loop {
+            match next(&mut events).await {
+                RelayEvent::Connecting { .. } | RelayEvent::RetryScheduled { .. } => continue,
+                RelayEvent::Registered { code: again, is_new } => {
+                    assert_eq!((again, is_new), (code, false));
+                    break
+                },
+                event => panic!("unexpected {event:?}"),
+            }
+        }
+
+        relay.shutdown().await;
+        assert!(matches!(next(&mut events).await, RelayEvent::Disconnected { reason } if reason == "shutting down"));
+    }
+
+    #[tokio::test]
+    async fn registers_as_not_ready_when_the_self_test_takes_too_long() {
+        let dir = TempDir::new("relay-self-test");
+        let (server, base_url) = spawn_relay_server(&dir).await;
+        let url = format!("{}/api/v1/provider", base_url.replacen("http://", "ws://", 1));
+        let handler = fake_handler(fixture_device_info());
+        let mut events = handler.events.subscribe();
+        // never started, so it has no result until run by hand
+        let self_test = Arc::new(SelfTest::new(handler.clone()));
+        let resource = RelayResource {
+            self_test_wait: Duration::from_millis(200),
+            ..RelayResource::new(url, Arc::new(MemoryStateStore::default()), handler, Some(self_test.clone()))
+        };
+        let relay = RelayResource::manager_builder().build(Arc::new(resource));
+
+        assert!(matches!(next(&mut events).await, RelayEvent::Connecting { .. }));
+        let RelayEvent::Registered { code, .. } = next(&mut events).await else { panic!("not registered") };
+        let self_test_status = || async {
+            loop {
+                let status = server.provider_status(&code).await.unwrap();
+                if let Some(report) = status.self_test {
+                    break (status.ready, report)
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let (ready, report) = self_test_status().await;
+        assert!(!ready && !report.passed);
+        assert_eq!(report.checks[0].error.as_deref(), Some("no result after 200ms"));
+
+        // the real result follows once there is one
+        assert!(self_test.run().await.passed);
+        loop {
+            if server.provider_status(&code).await.unwrap().ready {
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(self_test_status().await.1.passed);
+        relay.shutdown().await;
     }
 }
@@ -0,0 +1,177 @@
+//! Checks that the device can actually serve validation requests, when the provider registers and
+//! every few minutes after, and tells the relay. A broken absd connection or `abs-client`
+//! entitlement otherwise only shows up as every request failing.
+
+use std::{sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use backon::ExponentialBuilder;
+use tokio::{sync::watch, task::JoinHandle, time::Instant};
+
+use crate::{commands::CommandHandler, error::RelayError, nac::generate_validation_data, protocol::{SelfTestCheck, SelfTestReport}, util::{Resource, ResourceManagerBuilder}};
+
+pub struct SelfTest {
+    handler: Arc<CommandHandler>,
+    report: watch::Sender<Option<SelfTestReport>>,
+}
+
+impl SelfTest {
+    pub fn new(handler: Arc<CommandHandler>) -> SelfTest {
+        SelfTest { handler, report: watch::Sender::new(None) }
+    }
+
+    pub fn manager_builder() -> ResourceManagerBuilder<ExponentialBuilder> {
+        ResourceManagerBuilder::new()
+            .backoff(ExponentialBuilder::default()
+                .with_max_delay(Duration::from_secs(60))
+                .with_max_times(usize::MAX))
+            .health_interval(Duration::from_secs(300))
+            .health_timeout(Duration::from_secs(60))
+    }
+
+    /// The latest result, if the test has run
+    pub fn report(&self) -> Option<SelfTestReport> {
+        self.report.borrow().clone()
+    }
+
+    /// Notified with every new result
+    pub fn subscribe(&self) -> watch::Receiver<Option<SelfTestReport>> {
+        self.report.subscribe()
+    }
+
+    /// Generate validation data and read the device info, as a relay request would
+    pub async fn run(&self) -> SelfTestReport {
+        let started = Instant::now();
+        let validation = generate_validation_data(&*self.handler.nac).await.map(|_| ());
+        let validation = check("validation-data", started, validation);
+
+        let started = Instant::now();
+        let device_info = check("device-info", started, self.handler.device_info.versions().map(|_| ()));
+
+        let checks = vec![validation, device_info];
+        let report = SelfTestReport {
+            passed: checks.iter().all(|check| check.passed),
+            at_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            checks,
+        };
+        self.report.send_replace(Some(report.clone()));
+        report
+    }
+
+    async fn run_and_check(&self) -> Result<(), RelayError> {
+        let report = self.run().await;
+        if !report.passed {
+            let failed: Vec<String> = report.checks.iter()
+                .filter_map(|check| Some(format!("{}: {}", check.name, check.error.as_ref()?)))
+                .collect();
+            return Err(RelayError::SelfTestFailed(failed.join(", ")))
+        }
+        Ok(())
+    }
+}
+
+/// A failed report for a self-test that hasn't finished after `waited`
+pub fn timed_out(waited: Duration) -> SelfTestReport {
+    SelfTestReport {
+        passed: false,
+        at_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+        checks: vec![SelfTestCheck {
+            name: "self-test".to_string(),
+            passed: false,
+            error: Some(format!("no result after {waited:?}")),
+            latency_ms: waited.as_millis() as u64,
+        }],
+    }
+}
+
+fn check(name: &str, started: Instant, result: Result<(), RelayError>) -> SelfTestCheck {
+    if let Err(err) = &result {
+        println!("Self-test {name} failed: {err}");
+    }
+    SelfTestCheck {
+        name: name.to_string(),
+        passed: result.is_ok(),
+        error: result.err().map(|err| err.to_string()),
+        latency_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+impl Resource for SelfTest {
+    type Error = RelayError;
+
+    // up while the latest run passed; every run's report goes to the relay either way
+    async fn generate(self: &Arc<Self>) -> Result<JoinHandle<()>, RelayError> {
+        self.run_and_check().await?;
+        println!("Self-test passed");
+        Ok(tokio::spawn(std::future::pending()))
+    }
+
+    // catches absd or the entitlement breaking after startup
+    async fn health_check(self: &Arc<Self>) -> Result<(), RelayError> {
+        self.run_and_check().await
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::net::TcpListener;
+
+    use crate::{client::RelayClient, device::DeviceInfoProvider, error::RelayError, local_api, nac::FakeNacBackend, protocol::RelayVersions, provider::RelayProvider, qr::QrConfig, server::ServerError, testing::{spawn_relay_server, TempDir}};
+
+    /// MobileGestalt being unreadable
+    struct BrokenDeviceInfo;
+
+    impl DeviceInfoProvider for BrokenDeviceInfo {
+        fn versions(&self) -> Result<RelayVersions, RelayError> {
+            Err(RelayError::BadCommand("gestalt is unavailable".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_self_test_is_reported_as_not_ready() {
+        let dir = TempDir::new("self-test");
+        let (server, base_url) = spawn_relay_server(&dir).await;
+        let provider = RelayProvider::builder()
+            .url(format!("{}/api/v1/provider", base_url.replacen("http://", "ws://", 1)))
+            .nac_backend(Arc::new(FakeNacBackend::default()))
+            .device_info(Arc::new(BrokenDeviceInfo))
+            .self_test(true)
+            .allow_fake_nac(true)
+            .start().await.unwrap();
+
+        // still registers, so the relay can tell bridges why it isn't serving
+        let code = loop {
+            if let Some(state) = provider.state().await {
+                break state.code
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let status = loop {
+            let status = server.provider_status(&code).await.unwrap();
+            if status.self_test.is_some() {
+                break status
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert!(status.online && !status.ready);
+        assert!(matches!(server.request(&code, "get-version-info", None).await, Err(ServerError::ProviderNotReady)));
+        assert!(RelayClient::connect(&base_url, &code).await.is_err());
+
+        let relay = provider.relay().clone();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = local_api::router(relay.handler.clone(), Some(relay), QrConfig { terminal: false, deep_link: None }, "sekrit".to_string());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        let status: serde_json::Value = reqwest::Client::new().get(format!("http://{addr}/api/v1/status"))
+            .bearer_auth("sekrit")
+            .send().await.unwrap()
+            .json().await.unwrap();
+        assert_eq!(status["self_test"]["passed"], false);
+        let checks = status["self_test"]["checks"].as_array().unwrap();
+        assert!(checks.iter().any(|check| check["name"] == "device-info" && check["passed"] == false));
+        assert!(checks.iter().any(|check| check["name"] == "validation-data" && check["passed"] == true));
+
+        provider.stop().await;
+    }
+}
@@ -0,0 +1,398 @@
+//! A self-hostable registration relay: providers connect over a websocket and register for a
+//! code, bridges send commands for a code over HTTP and get the provider's response back.
+
+use std::{collections::HashMap, path::PathBuf, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::Duration};
+
+use axum::{body::Bytes, extract::{ws::{Message, WebSocket}, Path, State, WebSocketUpgrade}, http::{HeaderMap, StatusCode}, response::{IntoResponse, Response}, routing::{get, post}, Json, Router};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{net::TcpListener, select, sync::{mpsc, oneshot, Mutex}};
+
+use crate::protocol::{CommandData, ProviderStatus, RelayCommand, RelayState, SelfTestReport};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum ServerError {
+    #[error("No provider is registered with that code")]
+    UnknownProvider,
+    #[error("Provider is offline")]
+    ProviderOffline,
+    #[error("Provider is failing its self-test")]
+    ProviderNotReady,
+    #[error("Provider did not respond in time")]
+    Timeout,
+    #[error("Missing bearer code")]
+    MissingCode,
+    #[error("JSON error: {0}")]
+    JSONError(#[from] serde_json::Error),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+}
+
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ServerError::UnknownProvider => StatusCode::NOT_FOUND,
+            ServerError::ProviderOffline | ServerError::ProviderNotReady => StatusCode::SERVICE_UNAVAILABLE,
+            ServerError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ServerError::MissingCode => StatusCode::UNAUTHORIZED,
+            ServerError::JSONError(_) => StatusCode::BAD_REQUEST,
+            ServerError::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, Json(serde_json::json!({ "error": self.to_string() }))).into_response()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct RelayServerConfig {
+    pub bind: String,
+    /// Registered codes and secrets are kept here across restarts
+    pub state_path: PathBuf,
+}
+
+impl Default for RelayServerConfig {
+    fn default() -> Self {
+        RelayServerConfig {
+            // put it behind a TLS proxy rather than exposing it directly
+            bind: "127.0.0.1:8080".to_string(),
+            state_path: PathBuf::from("relay-state.json"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct PersistedState {
+    // code -> secret
+    providers: HashMap<String, String>,
+}
+
+struct ProviderConnection {
+    conn_id: u64,
+    sender: mpsc::UnboundedSender<RelayCommand>,
+    self_test: Option<SelfTestReport>,
+}
+
+impl ProviderConnection {
+    // providers that don't run a self-test are taken at their word
+    fn ready(&self) -> bool {
+        self.self_test.as_ref().is_none_or(|self_test| self_test.passed)
+    }
+}
+
+struct PendingRequest {
+    code: String,
+    sender: oneshot::Sender<Option<CommandData>>,
+}
+
+pub struct RelayServer {
+    config: RelayServerConfig,
+    registered: Mutex<PersistedState>,
+    online: Mutex<HashMap<String, ProviderConnection>>,
+    pending: Mutex<HashMap<u64, PendingRequest>>,
+    next_id: AtomicU64,
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric).take(len).map(char::from).collect()
+}
+
+impl RelayServer {
+    pub async fn load(config: RelayServerConfig) -> Result<Arc<RelayServer>, ServerError> {
+        let registered = match tokio::fs::read_to_string(&config.state_path).await {
+            Ok(read) => serde_json::from_str(&read)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => PersistedState::default(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Arc::new(RelayServer {
+            config,
+            registered: Mutex::new(registered),
+            online: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        }))
+    }
+
+    pub fn router(self: &Arc<Self>) -> Router {
+        Router::new()
+            .route("/api/v1/provider", get(provider_socket))
+            .route("/api/v1/providers/:code", get(provider_status))
+            .route("/api/v1/bridge/:command", post(bridge_command))
+            .with_state(self.clone())
+    }
+
+    pub async fn serve(self: Arc<Self>) -> Result<(), ServerError> {
+        let listener = TcpListener::bind(&self.config.bind).await?;
+        println!("Registration relay listening on {}", self.config.bind);
+        axum::serve(listener, self.router()).await?;
+        Ok(())
+    }
+
+    /// Reuse the requested registration if the secret matches, otherwise hand out a new one
+    async fn register(&self, requested: Option<RelayState>) -> Result<(RelayState, bool), ServerError> {
+        let mut registered = self.registered.lock().await;
+        if let Some(requested) = requested {
+            if registered.providers.get(&requested.code) == Some(&requested.secret) {
+                return Ok((requested, false))
+            }
+        }
+
+        let code = loop {
+            let code = format!("{}-{}-{}-{}", random_string(4), random_string(4), random_string(4), random_string(4)).to_uppercase();
+            if !registered.providers.contains_key(&code) {
+                break code
+            }
+        };
+        let state = RelayState { code, secret: random_string(32) };
+        registered.providers.insert(state.code.clone(), state.secret.clone());
+        tokio::fs::write(&self.config.state_path, serde_json::to_string(&*registered)?).await?;
+        Ok((state, true))
+    }
+
+    pub async fn provider_status(&self, code: &str) -> Result<ProviderStatus, ServerError> {
+        if !self.registered.lock().await.providers.contains_key(code) {
+            return Err(ServerError::UnknownProvider)
+        }
+        let online = self.online.lock().await;
+        let conn = online.get(code);
+        Ok(ProviderStatus {
+            code: code.to_string(),
+            online: conn.is_some(),
+            ready: conn.is_some_and(ProviderConnection::ready),
+            self_test: conn.and_then(|conn| conn.self_test.clone()),
+        })
+    }
+
+    /// Send a command to the provider registered under `code` and wait for its response
+    pub async fn request(&self, code: &str, command: &str, data: Option<CommandData>) -> Result<Option<CommandData>, ServerError> {
+        self.provider_status(code).await?;
+        let sender = match self.online.lock().await.get(code) {
+            None => return Err(ServerError::ProviderOffline),
+            Some(conn) if !conn.ready() => return Err(ServerError::ProviderNotReady),
+            Some(conn) => conn.sender.clone(),
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (send, confirm) = oneshot::channel();
+        self.pending.lock().await.insert(id, PendingRequest { code: code.to_string(), sender: send });
+        if sender.send(RelayCommand { command: command.to_string(), id: Some(id), data }).is_err() {
+            self.pending.lock().await.remove(&id);
+            return Err(ServerError::ProviderOffline)
+        }
+
+        match tokio::time::timeout(REQUEST_TIMEOUT, confirm).await {
+            Ok(Ok(data)) => Ok(data),
+            // dropped when the provider disconnected
+            Ok(Err(_)) => Err(ServerError::ProviderOffline),
+            Err(_) => {
+                self.pending.lock().await.remove(&id);
+                Err(ServerError::Timeout)
+            }
+        }
+    }
+
+    async fn handle_provider(self: Arc<Self>, mut socket: WebSocket) {
+        let Some(Ok(Message::Text(first))) = socket.recv().await else { return };
+        let Ok(register) = serde_json::from_str::<RelayCommand>(&first) else { return };
+        if register.command != "register" {
+            return
+        }
+        let requested = match register.data {
+            Some(CommandData::Code { code }) => Some(code),
+            _ => None,
+        };
+        let state = match self.register(requested).await {
+            Ok((state, is_new)) => {
+                println!("Provider registered with {} code {}", if is_new { "new" } else { "existing" }, state.code);
+                state
+            },
+            Err(err) => {
+                println!("Failed to register provider: {err}");
+                return
+            }
+        };
+        let response = RelayCommand { command: "response".to_string(), id: None, data: Some(CommandData::Code { code: state.clone() }) };
+        if socket.send(Message::Text(serde_json::to_string(&response).unwrap())).await.is_err() {
+            return
+        }
+
+        let conn_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, mut outgoing) = mpsc::unbounded_channel();
+        // a reconnecting provider replaces its old connection
+        self.online.lock().await.insert(state.code.clone(), ProviderConnection { conn_id, sender, self_test: None });
+
+        loop {
+            select! {
+                command = outgoing.recv() => {
+                    let Some(command) = command else { break };
+                    if socket.send(Message::Text(serde_json::to_string(&command).unwrap())).await.is_err() {
+                        break
+                    }
+                },
+                msg = socket.recv() => {
+                    let text = match msg {
+                        Some(Ok(Message::Text(text))) => text,
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => continue,
+                    };
+                    let Ok(command) = serde_json::from_str::<RelayCommand>(&text) else {
+                        println!("Bad message from provider {}: {text}", state.code);
+                        continue
+                    };
+                    match command.command.as_str() {
+                        "ping" => {
+                            let pong = RelayCommand { command: "pong".to_string(), id: None, data: None };
+                            if socket.send(Message::Text(serde_json::to_string(&pong).unwrap())).await.is_err() {
+                                break
+                            }
+                        },
+                        "self-test" => {
+                            let Some(CommandData::SelfTest { self_test }) = command.data else { continue };
+                            println!("Provider {} self-test {}", state.code, if self_test.passed { "passed" } else { "failed" });
+                            if let Some(conn) = self.online.lock().await.get_mut(&state.code).filter(|conn| conn.conn_id == conn_id) {
+                                conn.self_test = Some(self_test);
+                            }
+                        },
+                        "response" => {
+                            let Some(id) = command.id else { continue };
+                            let mut pending = self.pending.lock().await;
+                            // ids are sequential, so don't let a provider answer for another code
+                            if pending.get(&id).is_none_or(|pending| pending.code != state.code) {
+                                println!("Provider {} answered request {id}, which isn't one of its own", state.code);
+                                continue
+                            }
+                            if let Some(pending) = pending.remove(&id) {
+                                let _ = pending.sender.send(command.data);
+                            }
+                        },
+                        _ => {},
+                    }
+                }
+            }
+        }
+
+        let mut online = self.online.lock().await;
+        if online.get(&state.code).is_some_and(|conn| conn.conn_id == conn_id) {
+            online.remove(&state.code);
+            // fail anything still waiting on this provider right away
+            self.pending.lock().await.retain(|_, pending| pending.code != state.code);
+        }
+        println!("Provider {} disconnected", state.code);
+    }
+}
+
+async fn provider_socket(State(server): State<Arc<RelayServer>>, upgrade: WebSocketUpgrade) -> Response {
+    upgrade.on_upgrade(move |socket| server.handle_provider(socket))
+}
+
+async fn provider_status(State(server): State<Arc<RelayServer>>, Path(code): Path<String>) -> Result<Json<ProviderStatus>, ServerError> {
+    Ok(Json(server.provider_status(&code).await?))
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers.get("Authorization")?.to_str().ok()?.strip_prefix("Bearer ")
+}
+
+async fn bridge_command(State(server): State<Arc<RelayServer>>, Path(command): Path<String>, headers: HeaderMap, body: Bytes) -> Result<Json<Option<CommandData>>, ServerError> {
+    let code = bearer(&headers).ok_or(ServerError::MissingCode)?;
+    let data = if body.is_empty() { None } else { Some(serde_json::from_slice(&body)?) };
+    Ok(Json(server.request(code, &command, data).await?))
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+    use crate::{client::RelayClient, protocol::{CommandData, RelayCommand}, testing::{fake_handler, fixture_device_info, spawn_relay_server, start_provider, TempDir}};
+
+    use super::{RelayServer, ServerError};
+
+    type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    async fn receive(socket: &mut Socket) -> RelayCommand {
+        serde_json::from_str(&socket.next().await.unwrap().unwrap().into_text().unwrap()).unwrap()
+    }
+
+    /// A provider speaking the protocol by hand, once the server has it online
+    async fn raw_provider(server: &RelayServer, base_url: &str) -> (Socket, String) {
+        let (mut socket, _) = connect_async(format!("{}/api/v1/provider", base_url.replacen("http://", "ws://", 1))).await.unwrap();
+        socket.send(RelayCommand { command: "register".to_string(), id: None, data: Some(CommandData::Empty {}) }.into_message()).await.unwrap();
+        let Some(CommandData::Code { code }) = receive(&mut socket).await.data else { panic!("no code") };
+        while !server.provider_status(&code.code).await.unwrap().online {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        (socket, code.code)
+    }
+
+    fn validation_data(data: &str) -> CommandData {
+        CommandData::ValidationData { data: data.to_string() }
+    }
+
+    #[tokio::test]
+    async fn bridges_commands_to_a_provider() {
+        let dir = TempDir::new("server");
+        let (server, base_url) = spawn_relay_server(&dir).await;
+        let (relay, code) = start_provider(&base_url, fake_handler(fixture_device_info())).await;
+        let status = server.provider_status(&code).await.unwrap();
+        assert!(status.online && status.ready);
+
+        let client = RelayClient::new(&base_url, &code);
+        assert_eq!(client.get_version_info().await.unwrap().software_build_id, "20H343");
+        assert!(client.get_validation_data().await.unwrap().starts_with(b"FAKENAC"));
+        assert!(client.sign_payload(b"payload").await.unwrap().ends_with(b"payload"));
+
+        relay.shutdown().await;
+        while server.provider_status(&code).await.unwrap().online {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(matches!(server.request(&code, "get-validation-data", None).await, Err(ServerError::ProviderOffline)));
+        assert!(matches!(server.request("NOPE", "get-validation-data", None).await, Err(ServerError::UnknownProvider)));
+    }
+
+    #[tokio::test]
+    async fn reconnecting_provider_keeps_its_code() {
+        let dir = TempDir::new("server");
+        let (server, base_url) = spawn_relay_server(&dir).await;
+        let (relay, code) = start_provider(&base_url, fake_handler(fixture_device_info())).await;
+
+        relay.request_update().await;
+        relay.subscribe_generation().wait_for(|generation| *generation == 2).await.unwrap();
+
+        assert_eq!(relay.state.lock().await.as_ref().unwrap().code, code);
+        assert!(dir.path().join("relay-state.json").exists());
+        while !server.provider_status(&code).await.unwrap().online {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        relay.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn providers_cannot_answer_each_others_requests() {
+        let dir = TempDir::new("server");
+        let (server, base_url) = spawn_relay_server(&dir).await;
+        let (mut honest, code) = raw_provider(&server, &base_url).await;
+        let (mut forger, _) = raw_provider(&server, &base_url).await;
+
+        let request = tokio::spawn({
+            let server = server.clone();
+            async move { server.request(&code, "get-validation-data", None).await }
+        });
+        let command = receive(&mut honest).await;
+        assert_eq!(command.command, "get-validation-data");
+
+        forger.send(command.respond(validation_data("forged"))).await.unwrap();
+        // the server handles a provider's messages in order, so the pong means the forgery was seen
+        forger.send(RelayCommand { command: "ping".to_string(), id: None, data: None }.into_message()).await.unwrap();
+        assert_eq!(receive(&mut forger).await.command, "pong");
+        assert!(!request.is_finished());
+
+        honest.send(command.respond(validation_data("genuine"))).await.unwrap();
+        let Some(CommandData::ValidationData { data }) = request.await.unwrap().unwrap() else { panic!("no validation data") };
+        assert_eq!(data, "genuine");
+    }
+}
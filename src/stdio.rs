@@ -0,0 +1,276 @@
+//! Line-delimited JSON-RPC 2.0 over stdin/stdout, for parent processes that would rather spawn
+//! the provider than talk to it over a socket.
+//!
+//! Methods are the provider commands (`get-version-info`, `get-validation-data`, `sign-payload`)
+//! with the websocket protocol's `CommandData` as params and result. Relay events are sent as
+//! `event` notifications.
+
+use std::{fs::File, io::Write, os::fd::{FromRawFd, RawFd}, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader}, sync::{broadcast, mpsc}};
+
+use crate::{commands::CommandHandler, error::RelayError, events::RelayEvent, protocol::{CommandData, RelayCommand}};
+
+const PARSE_ERROR: i32 = -32700;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+// the command ran but the provider couldn't answer it
+const PROVIDER_ERROR: i32 = -32000;
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    // absent for notifications, which get no response
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Option<Value>,
+}
+
+#[derive(Serialize)]
+struct RpcError {
+    code: i32,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum RpcOutput {
+    Result {
+        jsonrpc: &'static str,
+        id: Value,
+        result: CommandData,
+    },
+    Error {
+        jsonrpc: &'static str,
+        id: Value,
+        error: RpcError,
+    },
+    Notification {
+        jsonrpc: &'static str,
+        method: &'static str,
+        params: RelayEvent,
+    },
+}
+
+impl RpcOutput {
+    fn error(id: Value, code: i32, message: String, data: Option<Value>) -> RpcOutput {
+        RpcOutput::Error { jsonrpc: "2.0", id, error: RpcError { code, message, data } }
+    }
+}
+
+/// Point fd 1 at stderr so logs (ours and the C shim's) stay out of the protocol, and return the
+/// original stdout for the protocol to use.
+pub fn take_stdout() -> Result<File, RelayError> {
+    std::io::stdout().flush()?;
+    let protocol = dup_cloexec(1)?;
+    // SAFETY: fds 1 and 2 are always open; nothing holds on to the old fd 1 but `protocol`
+    if unsafe { libc::dup2(2, 1) } < 0 {
+        return Err(std::io::Error::last_os_error().into())
+    }
+    Ok(protocol)
+}
+
+// close-on-exec, so hook commands don't inherit the protocol channel
+fn dup_cloexec(fd: RawFd) -> Result<File, RelayError> {
+    // SAFETY: the dup'd fd is owned by the returned File alone
+    unsafe {
+        let dup = libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0);
+        if dup < 0 {
+            return Err(std::io::Error::last_os_error().into())
+        }
+        Ok(File::from_raw_fd(dup))
+    }
+}
+
+async fn answer(handler: &CommandHandler, request: RpcRequest) -> Option<RpcOutput> {
+    let id = request.id?;
+    let data = match request.params.map(serde_json::from_value::<CommandData>).transpose() {
+        Ok(data) => data,
+        Err(err) => return Some(RpcOutput::error(id, INVALID_PARAMS, err.to_string(), None)),
+    };
+    // lets numeric ids be matched up with the validation events they cause
+    let command = RelayCommand { command: request.method, id: id.as_u64(), data };
+    Some(match handler.handle(&command).await {
+        Some(CommandData::Error { error, nac_code, transient }) =>
+            RpcOutput::error(id, PROVIDER_ERROR, error, Some(serde_json::json!({ "nac_code": nac_code, "transient": transient }))),
+        Some(result) => RpcOutput::Result { jsonrpc: "2.0", id, result },
+        None => RpcOutput::error(id, METHOD_NOT_FOUND, format!("Unknown method {}", command.command), None),
+    })
+}
+
+/// Answer requests from stdin until it closes, writing responses and events to `output`
+pub async fn serve_stdio(handler: Arc<CommandHandler>, output: File) -> Result<(), RelayError> {
+    serve_rpc(handler, tokio::io::stdin(), tokio::fs::File::from_std(output)).await
+}
+
+async fn serve_rpc(handler: Arc<CommandHandler>, input: impl AsyncRead + Unpin, mut output: impl AsyncWrite + Unpin + Send + 'static) -> Result<(), RelayError> {
+    let (send, mut outgoing) = mpsc::unbounded_channel::<RpcOutput>();
+
+    // one writer so concurrent responses don't interleave mid-line
+    let writer = tokio::spawn(async move {
+        while let Some(item) = outgoing.recv().await {
+            let mut line = serde_json::to_vec(&item)?;
+            line.push(b'\n');
+            output.write_all(&line).await?;
+            output.flush().await?;
+        }
+        Ok::<(), RelayError>(())
+    });
+
+    let mut events = handler.events.subscribe();
+    let event_send = send.clone();
+    let notifier = tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if event_send.send(RpcOutput::Notification { jsonrpc: "2.0", method: "event", params: event }).is_err() {
+                        break
+                    }
+                },
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let mut lines = BufReader::new(input).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue
+        }
+        let request = match serde_json::from_str::<RpcRequest>(&line) {
+            Ok(request) => request,
+            Err(err) => {
+                let _ = send.send(RpcOutput::error(Value::Null, PARSE_ERROR, err.to_string(), None));
+                continue
+            }
+        };
+        // validation data can take a while; don't hold up other requests behind it
+        let handler = handler.clone();
+        let send = send.clone();
+        tokio::spawn(async move {
+            if let Some(output) = answer(&handler, request).await {
+                let _ = send.send(output);
+            }
+        });
+    }
+
+    notifier.abort();
+    drop(send);
+    writer.await.map_err(|err| RelayError::IoError(std::io::Error::other(err)))?
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use std::{io::Read, os::fd::{AsRawFd, FromRawFd}};
+
+    use serde_json::{json, Value};
+    use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream, Lines, ReadHalf, WriteHalf}, task::JoinHandle};
+
+    use crate::{error::RelayError, events::RelayEvent, testing::{fake_handler, fixture_device_info}};
+
+    use super::{dup_cloexec, serve_rpc, INVALID_PARAMS, METHOD_NOT_FOUND, PARSE_ERROR};
+
+    struct Client {
+        input: WriteHalf<DuplexStream>,
+        output: Lines<BufReader<ReadHalf<DuplexStream>>>,
+    }
+
+    impl Client {
+        async fn send(&mut self, line: &str) {
+            self.input.write_all(format!("{line}\n").as_bytes()).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> Value {
+            serde_json::from_str(&self.output.next_line().await.unwrap().unwrap()).unwrap()
+        }
+    }
+
+    /// The RPC loop on an in-memory pipe, answering with the fake backend
+    fn spawn_rpc() -> (Client, tokio::sync::broadcast::Sender<RelayEvent>, JoinHandle<Result<(), RelayError>>) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (server_read, server_write) = tokio::io::split(server);
+        let (client_read, client_write) = tokio::io::split(client);
+        let handler = fake_handler(fixture_device_info());
+        let events = handler.events.clone();
+        let rpc = tokio::spawn(serve_rpc(handler, server_read, server_write));
+        (Client { input: client_write, output: BufReader::new(client_read).lines() }, events, rpc)
+    }
+
+    #[tokio::test]
+    async fn answers_requests_line_by_line() {
+        let (mut client, _events, rpc) = spawn_rpc();
+        // blank lines are skipped, and a request split across writes is still one line
+        client.send("").await;
+        client.input.write_all(br#"{"jsonrpc": "2.0", "id": "a", "#).await.unwrap();
+        client.send(r#""method": "get-version-info"}"#).await;
+        let response = client.recv().await;
+        assert_eq!(response["jsonrpc"], "2.0");
+        assert_eq!(response["id"], "a");
+        assert_eq!(response["result"]["versions"]["hardware_version"], "iPhone10,1");
+
+        // notifications get no response
+        client.send(r#"{"jsonrpc": "2.0", "method": "get-version-info"}"#).await;
+        client.send(r#"{"jsonrpc": "2.0", "id": 2, "method": "get-version-info"}"#).await;
+        assert_eq!(client.recv().await["id"], 2);
+
+        // the loop ends when the input closes
+        client.input.shutdown().await.unwrap();
+        rpc.await.unwrap().unwrap();
+        assert!(client.output.next_line().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_json_rpc_errors() {
+        let (mut client, _events, _rpc) = spawn_rpc();
+
+        client.send("{not json").await;
+        let response = client.recv().await;
+        assert_eq!((response["id"].clone(), response["error"]["code"].clone()), (Value::Null, json!(PARSE_ERROR)));
+
+        client.send(r#"{"jsonrpc": "2.0", "id": 1, "method": "reboot"}"#).await;
+        let response = client.recv().await;
+        assert_eq!((response["id"].clone(), response["error"]["code"].clone()), (json!(1), json!(METHOD_NOT_FOUND)));
+        assert_eq!(response["error"]["message"], "Unknown method reboot");
+
+        client.send(r#"{"jsonrpc": "2.0", "id": 2, "method": "sign-payload", "params": 5}"#).await;
+        let response = client.recv().await;
+        assert_eq!((response["id"].clone(), response["error"]["code"].clone()), (json!(2), json!(INVALID_PARAMS)));
+    }
+
+    #[tokio::test]
+    async fn forwards_events_as_notifications() {
+        let (mut client, events, _rpc) = spawn_rpc();
+        // wait for the loop to be up, so it has subscribed before the event is sent
+        client.send(r#"{"jsonrpc": "2.0", "id": 1, "method": "get-version-info"}"#).await;
+        client.recv().await;
+
+        events.send(RelayEvent::Disconnected { reason: "connection closed".to_string() }).unwrap();
+        let notification = client.recv().await;
+        assert_eq!(notification, json!({ "jsonrpc": "2.0", "method": "event", "params": { "event": "disconnected", "reason": "connection closed" } }));
+        assert!(notification.get("id").is_none());
+    }
+
+    #[test]
+    fn protocol_fd_is_closed_on_exec() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // SAFETY: both ends were just opened and are owned by these Files alone
+        let (mut read, write) = unsafe { (std::fs::File::from_raw_fd(fds[0]), std::fs::File::from_raw_fd(fds[1])) };
+
+        let mut protocol = dup_cloexec(write.as_raw_fd()).unwrap();
+        drop(write);
+        let flags = unsafe { libc::fcntl(protocol.as_raw_fd(), libc::F_GETFD) };
+        assert_ne!(flags & libc::FD_CLOEXEC, 0);
+
+        std::io::Write::write_all(&mut protocol, b"still the same pipe").unwrap();
+        drop(protocol);
+        let mut written = String::new();
+        read.read_to_string(&mut written).unwrap();
+        assert_eq!(written, "still the same pipe");
+    }
+}
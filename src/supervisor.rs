@@ -0,0 +1,336 @@
+//! Runs several `ResourceManager`s together: each resource only generates while the resources it
+//! depends on are up, and is restarted whenever one of them regenerates.
+
+use std::sync::Arc;
+
+use backon::BackoffBuilder;
+use futures::{future::BoxFuture, FutureExt};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::{select, sync::watch, task::JoinHandle};
+
+use crate::util::{Resource, ResourceManager, ResourceManagerBuilder, ResourceState};
+
+#[derive(Error, Debug)]
+pub enum SupervisorError {
+    #[error("Resource {0} is already supervised")]
+    Duplicate(String),
+    #[error("Resource {resource} depends on {dependency}, which hasn't been added")]
+    UnknownDependency { resource: String, dependency: String },
+}
+
+/// One resource's state, without its error type
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "kebab-case")]
+pub enum Health {
+    /// Waiting on its dependencies
+    Waiting,
+    Starting,
+    Up,
+    /// Failed, retrying
+    Down { error: String },
+    /// Failed, not retrying
+    Failed { error: String },
+}
+
+impl<E: crate::util::ResourceError> From<&ResourceState<E>> for Health {
+    fn from(state: &ResourceState<E>) -> Self {
+        match state {
+            ResourceState::Waiting => Health::Waiting,
+            ResourceState::Generating => Health::Starting,
+            ResourceState::Generated => Health::Up,
+            ResourceState::Lost(error) => Health::Down { error: error.to_string() },
+            ResourceState::Failed(failure) if failure.retry_wait.is_some() => Health::Down { error: failure.error.to_string() },
+            ResourceState::Failed(failure) => Health::Failed { error: failure.error.to_string() },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverallHealth {
+    Healthy,
+    Starting,
+    /// Something is down but still retrying
+    Degraded,
+    /// Something gave up for good
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ResourceHealth {
+    pub name: String,
+    pub depends_on: Vec<String>,
+    #[serde(flatten)]
+    pub health: Health,
+    pub generation: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SupervisorHealth {
+    pub overall: OverallHealth,
+    pub resources: Vec<ResourceHealth>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct MemberStatus {
+    health: Health,
+    generation: u64,
+}
+
+// the parts of a ResourceManager the supervisor needs, whatever it manages
+trait Supervised: Send + Sync {
+    fn request_update(&self) -> BoxFuture<'_, ()>;
+
+    fn shutdown(&self) -> BoxFuture<'_, ()>;
+}
+
+impl<T: Resource + 'static> Supervised for ResourceManager<T> {
+    fn request_update(&self) -> BoxFuture<'_, ()> {
+        ResourceManager::request_update(self).boxed()
+    }
+
+    fn shutdown(&self) -> BoxFuture<'_, ()> {
+        ResourceManager::shutdown(self).boxed()
+    }
+}
+
+struct Member {
+    name: String,
+    depends_on: Vec<String>,
+    manager: Arc<dyn Supervised>,
+    status: watch::Receiver<MemberStatus>,
+}
+
+/// Resources started in the order they were added, each after its dependencies
+#[derive(Default)]
+pub struct Supervisor {
+    members: Vec<Member>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    pub fn new() -> Supervisor {
+        Supervisor::default()
+    }
+
+    /// Start supervising `resource`. Dependencies have to be added first, so there can't be cycles.
+    pub fn add<T: Resource + 'static, B: BackoffBuilder + 'static>(&mut self, name: &str, depends_on: &[&str], builder: ResourceManagerBuilder<B>, resource: Arc<T>) -> Result<Arc<ResourceManager<T>>, SupervisorError> {
+        if self.member(name).is_some() {
+            return Err(SupervisorError::Duplicate(name.to_string()))
+        }
+        let mut dependencies = vec![];
+        for dependency in depends_on {
+            let member = self.member(dependency).ok_or_else(|| SupervisorError::UnknownDependency {
+                resource: name.to_string(),
+                dependency: dependency.to_string(),
+            })?;
+            dependencies.push(member.status.clone());
+        }
+
+        let (gate, gate_recv) = watch::channel(dependencies.is_empty());
+        let manager = builder.gate(gate_recv).build(resource);
+
+        let (status, status_recv) = watch::channel(MemberStatus { health: Health::from(&manager.state()), generation: manager.generation() });
+        let mut states = manager.subscribe_state();
+        let mut generations = manager.subscribe_generation();
+        self.tasks.push(tokio::spawn(async move {
+            loop {
+                let health = Health::from(&*states.borrow_and_update());
+                let generation = *generations.borrow_and_update();
+                status.send_if_modified(|status| {
+                    let changed = status.health != health || status.generation != generation;
+                    *status = MemberStatus { health, generation };
+                    changed
+                });
+                let closed = select! {
+                    changed = states.changed() => changed.is_err(),
+                    changed = generations.changed() => changed.is_err(),
+                };
+                if closed {
+                    break
+                }
+            }
+        }));
+
+        if !dependencies.is_empty() {
+            self.tasks.push(tokio::spawn(follow_dependencies(name.to_string(), dependencies, gate, manager.clone())));
+        }
+
+        self.members.push(Member {
+            name: name.to_string(),
+            depends_on: depends_on.iter().map(|dependency| dependency.to_string()).collect(),
+            manager: manager.clone(),
+            status: status_recv,
+        });
+        Ok(manager)
+    }
+
+    fn member(&self, name: &str) -> Option<&Member> {
+        self.members.iter().find(|member| member.name == name)
+    }
+
+    pub fn health(&self) -> SupervisorHealth {
+        let resources: Vec<ResourceHealth> = self.members.iter().map(|member| {
+            let status = member.status.borrow().clone();
+            ResourceHealth {
+                name: member.name.clone(),
+                depends_on: member.depends_on.clone(),
+                health: status.health,
+                generation: status.generation,
+            }
+        }).collect();
+
+        let overall = if resources.iter().any(|resource| matches!(resource.health, Health::Failed { .. })) {
+            OverallHealth::Failed
+        } else if resources.iter().any(|resource| matches!(resource.health, Health::Down { .. })) {
+            OverallHealth::Degraded
+        } else if resources.iter().any(|resource| resource.health != Health::Up) {
+            OverallHealth::Starting
+        } else {
+            OverallHealth::Healthy
+        };
+        SupervisorHealth { overall, resources }
+    }
+
+    /// Shut everything down, dependents before what they depend on
+    pub async fn shutdown(&self) {
+        for member in self.members.iter().rev() {
+            member.manager.shutdown().await;
+        }
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+// holds the gate open while every dependency is up, and restarts the dependent when one regenerates
+// or goes down, so it waits at the gate instead of running without it
+async fn follow_dependencies(name: String, mut dependencies: Vec<watch::Receiver<MemberStatus>>, gate: watch::Sender<bool>, dependent: Arc<dyn Supervised>) {
+    let mut seen: Vec<u64> = dependencies.iter().map(|dependency| dependency.borrow().generation).collect();
+    let mut was_ready = false;
+    loop {
+        let mut regenerated = false;
+        let mut ready = true;
+        for (dependency, seen) in dependencies.iter_mut().zip(seen.iter_mut()) {
+            let status = dependency.borrow_and_update();
+            ready &= status.health == Health::Up;
+            // the first generation is what the gate waits for; only later ones invalidate the dependent
+            regenerated |= *seen != 0 && status.generation != *seen;
+            *seen = status.generation;
+        }
+        gate.send_if_modified(|open| std::mem::replace(open, ready) != ready);
+        // once stopped, the dependent is already waiting at the gate for whatever comes back up
+        if was_ready && regenerated {
+            println!("A dependency of {name} regenerated, restarting it");
+            dependent.request_update().await;
+        } else if was_ready && !ready {
+            println!("A dependency of {name} went down, stopping it");
+            dependent.request_update().await;
+        }
+        was_ready = ready;
+
+        let changes = dependencies.iter_mut().map(|dependency| dependency.changed().boxed());
+        if futures::future::select_all(changes).await.0.is_err() {
+            break
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, time::Duration};
+
+    use backon::ConstantBuilder;
+
+    use crate::{testing::TestResource, util::ResourceManagerBuilder};
+
+    use super::{Health, OverallHealth, Supervisor, SupervisorError};
+
+    fn builder() -> ResourceManagerBuilder<ConstantBuilder> {
+        ResourceManagerBuilder::new()
+            .backoff(ConstantBuilder::default().with_delay(Duration::from_secs(10)).with_max_times(usize::MAX))
+            .regen_debounce(Duration::ZERO)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dependent_waits_for_and_follows_dependency() {
+        let nac = Arc::new(TestResource { broken: AtomicBool::new(true), ..Default::default() });
+        let relay = Arc::new(TestResource::default());
+        let mut supervisor = Supervisor::new();
+        let nac_manager = supervisor.add("nac", &[], builder(), nac.clone()).unwrap();
+        supervisor.add("relay", &["nac"], builder(), relay.clone()).unwrap();
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(relay.attempts(), 0);
+        let health = supervisor.health();
+        assert_eq!(health.overall, OverallHealth::Degraded);
+        assert_eq!(health.resources[1].health, Health::Waiting);
+
+        nac.broken.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(relay.attempts(), 1);
+        assert_eq!(supervisor.health().overall, OverallHealth::Healthy);
+
+        nac_manager.refresh_now().await.unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(relay.attempts(), 2);
+        assert_eq!(supervisor.health().resources[1].generation, 2);
+
+        supervisor.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dependent_stops_while_dependency_is_down() {
+        let nac = Arc::new(TestResource::default());
+        let relay = Arc::new(TestResource::default());
+        let mut supervisor = Supervisor::new();
+        supervisor.add("nac", &[], builder().health_interval(Duration::from_secs(30)), nac.clone()).unwrap();
+        let relay_manager = supervisor.add("relay", &["nac"], builder(), relay.clone()).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(relay.attempts(), 1);
+
+        nac.broken.store(true, Ordering::SeqCst);
+        nac.sick.store(true, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert_eq!(*relay.aborts.lock().unwrap(), ["regenerating"]);
+        assert_eq!(Health::from(&relay_manager.state()), Health::Waiting);
+        assert_eq!(supervisor.health().overall, OverallHealth::Degraded);
+
+        nac.broken.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(relay.attempts(), 2);
+        assert_eq!(supervisor.health().overall, OverallHealth::Healthy);
+
+        supervisor.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn dependencies_must_exist() {
+        let mut supervisor = Supervisor::new();
+        let added = supervisor.add("relay", &["nac"], builder(), Arc::new(TestResource::default()));
+        assert!(matches!(added, Err(SupervisorError::UnknownDependency { .. })));
+
+        supervisor.add("nac", &[], builder(), Arc::new(TestResource::default())).unwrap();
+        let added = supervisor.add("nac", &[], builder(), Arc::new(TestResource::default()));
+        assert!(matches!(added, Err(SupervisorError::Duplicate(_))));
+        supervisor.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_does_not_release_dependents() {
+        let nac = Arc::new(TestResource { broken: AtomicBool::new(true), ..Default::default() });
+        let relay = Arc::new(TestResource::default());
+        let mut supervisor = Supervisor::new();
+        supervisor.add("nac", &[], builder(), nac).unwrap();
+        let relay_manager = supervisor.add("relay", &["nac"], builder(), relay.clone()).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(Health::from(&relay_manager.state()), Health::Waiting);
+
+        supervisor.shutdown().await;
+        drop(supervisor);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(relay.attempts(), 0);
+        assert_eq!(Health::from(&relay_manager.state()), Health::Waiting);
+    }
+}
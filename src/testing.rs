@@ -0,0 +1,153 @@
+//! Shared pieces for the unit tests.
+
+use std::{collections::VecDeque, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, AtomicU32, Ordering}, Arc}, time::Duration};
+
+use tokio::task::JoinHandle;
+#[cfg(feature = "host")]
+use tokio::{net::TcpListener, sync::broadcast};
+
+use crate::{error::RelayError, util::Resource};
+
+#[cfg(feature = "host")]
+use crate::{commands::CommandHandler, device::{DeviceInfoProvider, FixtureDeviceInfo}, nac::FakeNacBackend, provider::MemoryStateStore, relay::{Relay, RelayResource}, server::{RelayServer, RelayServerConfig}};
+
+static NEXT_DIR: AtomicU32 = AtomicU32::new(0);
+
+/// A fresh directory under the system temp dir, removed again on drop
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("relayserver-{name}-{}-{}", std::process::id(), NEXT_DIR.fetch_add(1, Ordering::Relaxed)));
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Fails while `broken` is set, then works through its script (an error to fail with, or `None` to
+/// succeed), then succeeds. Generated tasks run for `lifetime`, or until aborted. `sick` fails the
+/// next health check, `stuck` makes the next one hang, and `panic_generate` / `panic_task` make the
+/// next generate or generated task panic. `aborts` has the reasons the manager gave for aborting
+/// it. Each generated task holds a clone of `live`.
+#[derive(Default)]
+pub struct TestResource {
+    pub broken: AtomicBool,
+    pub script: std::sync::Mutex<VecDeque<Option<RelayError>>>,
+    pub attempts: AtomicU32,
+    pub flaps_reported: AtomicU32,
+    pub sick: AtomicBool,
+    pub stuck: AtomicBool,
+    pub panic_generate: AtomicBool,
+    pub panic_task: AtomicBool,
+    pub lifetime: Option<Duration>,
+    pub hang: bool,
+    pub aborts: std::sync::Mutex<Vec<String>>,
+    pub live: Arc<()>,
+}
+
+impl TestResource {
+    pub fn failing(failures: impl IntoIterator<Item = RelayError>) -> Arc<TestResource> {
+        Arc::new(TestResource { script: std::sync::Mutex::new(failures.into_iter().map(Some).collect()), ..Default::default() })
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts.load(Ordering::SeqCst)
+    }
+}
+
+impl Resource for TestResource {
+    type Error = RelayError;
+
+    async fn generate(self: &Arc<Self>) -> Result<JoinHandle<()>, RelayError> {
+        self.attempts.fetch_add(1, Ordering::SeqCst);
+        if self.hang {
+            std::future::pending::<()>().await;
+        }
+        if self.broken.load(Ordering::SeqCst) {
+            return Err(RelayError::NacAgentUnavailable("broken".to_string()))
+        }
+        if let Some(Some(err)) = self.script.lock().unwrap().pop_front() {
+            return Err(err)
+        }
+        let attempt = self.attempts();
+        if self.panic_generate.swap(false, Ordering::SeqCst) {
+            panic!("generate panicked on attempt {attempt}");
+        }
+        let lifetime = self.lifetime;
+        let panic_task = self.panic_task.swap(false, Ordering::SeqCst);
+        let live = self.live.clone();
+        Ok(tokio::spawn(async move {
+            let _live = live;
+            if panic_task {
+                panic!("task panicked on attempt {attempt}");
+            }
+            match lifetime {
+                Some(lifetime) => tokio::time::sleep(lifetime).await,
+                None => std::future::pending().await,
+            }
+        }))
+    }
+
+    fn flapping(&self, _unstable_runs: u32, _retry_in: Duration) {
+        self.flaps_reported.fetch_add(1, Ordering::SeqCst);
+    }
+
+    async fn health_check(self: &Arc<Self>) -> Result<(), RelayError> {
+        if self.sick.swap(false, Ordering::SeqCst) {
+            return Err(RelayError::RelayUnresponsive(Duration::from_secs(60)))
+        }
+        if self.stuck.swap(false, Ordering::SeqCst) {
+            std::future::pending::<()>().await;
+        }
+        Ok(())
+    }
+
+    fn aborted(&self, reason: &str) {
+        self.aborts.lock().unwrap().push(reason.to_string());
+    }
+}
+
+
+/// Answers from `fixtures/device-info.json`
+#[cfg(feature = "host")]
+pub fn fixture_device_info() -> Arc<dyn DeviceInfoProvider> {
+    Arc::new(FixtureDeviceInfo::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/device-info.json")).unwrap())
+}
+
+/// Commands answered by the fake NAC backend and the fixture device info
+#[cfg(feature = "host")]
+pub fn fake_handler(device_info: Arc<dyn DeviceInfoProvider>) -> Arc<CommandHandler> {
+    let (events, _) = broadcast::channel(99);
+    Arc::new(CommandHandler::new(Arc::new(FakeNacBackend::default()), device_info, events))
+}
+
+/// A relay server on a free local port, keeping its state in `dir`; returns its http:// root
+#[cfg(feature = "host")]
+pub async fn spawn_relay_server(dir: &TempDir) -> (Arc<RelayServer>, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = RelayServer::load(RelayServerConfig { bind: addr.to_string(), state_path: dir.path().join("relay-state.json") }).await.unwrap();
+    let router = server.router();
+    tokio::spawn(async move { axum::serve(listener, router).await });
+    (server, format!("http://{addr}"))
+}
+
+/// A provider connected to the relay at `base_url`, once it has registered; returns its code
+#[cfg(feature = "host")]
+pub async fn start_provider(base_url: &str, handler: Arc<CommandHandler>) -> (Relay, String) {
+    let url = format!("{}/api/v1/provider", base_url.replacen("http://", "ws://", 1));
+    let relay = RelayResource::manager_builder().build(Arc::new(RelayResource::new(url, Arc::new(MemoryStateStore::default()), handler, None)));
+    relay.wait_until_generated(Duration::from_secs(10)).await.unwrap();
+    let code = relay.state.lock().await.as_ref().unwrap().code.clone();
+    (relay, code)
+}
@@ -1,41 +1,148 @@
-use std::{fmt::Display, ops::Deref, sync::Arc, time::{Duration, SystemTime}};
+use std::{collections::VecDeque, fmt::Display, ops::Deref, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, SystemTime, UNIX_EPOCH}};
 
-use backon::BackoffBuilder;
+use backon::{BackoffBuilder, ExponentialBuilder};
+use rand::Rng;
 use thiserror::Error;
-use tokio::{select, sync::{broadcast, mpsc, oneshot, Mutex}, task::JoinHandle};
+use tokio::{select, sync::{mpsc, oneshot, watch, Mutex}, task::JoinHandle, time::Instant};
 
-use crate::error::RelayError;
 use futures::FutureExt;
 
+use crate::crash::{install_panic_hook, panic_message, take_panic, CrashReportConfig};
+
+/// Errors a `Resource` can fail to generate with
+pub trait ResourceError: std::error::Error + Send + Sync + 'static {
+    /// Whether the manager should give up instead of retrying
+    fn is_final(&self) -> bool {
+        false
+    }
+}
+
+/// Why a `ResourceManager` call failed
+#[derive(Error, Debug)]
+pub enum ManagerError<E: ResourceError> {
+    #[error("{0}")]
+    Failed(Arc<E>),
+    #[error("Resource Panic {0}")]
+    Panic(String),
+    #[error("Health check failed: {0}")]
+    Unhealthy(Arc<E>),
+    #[error("Resource Timeout")]
+    Timeout,
+    /// The generated task returned (or was cancelled) while the manager still wanted it running
+    #[error("Resource task exited")]
+    Exited,
+    #[error("Resource manager shut down")]
+    Shutdown,
+}
+
+// derived Clone would want E: Clone
+impl<E: ResourceError> Clone for ManagerError<E> {
+    fn clone(&self) -> Self {
+        match self {
+            ManagerError::Failed(err) => ManagerError::Failed(err.clone()),
+            ManagerError::Panic(message) => ManagerError::Panic(message.clone()),
+            ManagerError::Unhealthy(err) => ManagerError::Unhealthy(err.clone()),
+            ManagerError::Timeout => ManagerError::Timeout,
+            ManagerError::Exited => ManagerError::Exited,
+            ManagerError::Shutdown => ManagerError::Shutdown,
+        }
+    }
+}
+
+impl<E: ResourceError> ManagerError<E> {
+    fn is_final(&self) -> bool {
+        match self {
+            ManagerError::Failed(err) => err.is_final(),
+            _ => false,
+        }
+    }
+}
+
 pub trait Resource: Send + Sync + Sized {
+    type Error: ResourceError;
+
     // resolve when resource is done
-    fn generate(self: &Arc<Self>) -> impl std::future::Future<Output = Result<JoinHandle<()>, RelayError>> + Send;
+    fn generate(self: &Arc<Self>) -> impl std::future::Future<Output = Result<JoinHandle<()>, Self::Error>> + Send;
+
+    // called when generate failed and the manager will try again after `retry_in`
+    fn retry_scheduled(&self, _error: &ManagerError<Self::Error>, _retry_in: Duration) {}
+
+    // called when the resource keeps dying before it's stable; the next generate is in `retry_in`
+    fn flapping(&self, _unstable_runs: u32, _retry_in: Duration) {}
+
+    // run every `health_interval` while the resource is up; an error (or a check that outlasts the
+    // health timeout) regenerates it, which catches resources that hang without their task exiting
+    fn health_check(self: &Arc<Self>) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send {
+        async { Ok(()) }
+    }
+
+    // called when a health check failed and the resource is about to be regenerated
+    fn unhealthy(&self, _error: &ManagerError<Self::Error>) {}
+
+    // called when the manager aborts the generated task while it's still running (to regenerate it,
+    // after a failed health check, or on shutdown), since the task never gets to see itself end
+    fn aborted(&self, _reason: &str) {}
 
-    fn generate_unwind_safe(self: &Arc<Self>) -> impl std::future::Future<Output = Result<JoinHandle<()>, RelayError>> + Send {
+    fn generate_unwind_safe(self: &Arc<Self>) -> impl std::future::Future<Output = Result<JoinHandle<()>, ManagerError<Self::Error>>> + Send {
         async {
             std::panic::AssertUnwindSafe(self.generate())
                 .catch_unwind().await
                 .map_err(|e| {
-                    println!("paniced with {:?}", e.downcast_ref::<&str>());
-                    RelayError::ResourcePanic(e.downcast_ref::<&str>().unwrap_or(&"failed to str!").to_string())
+                    let message = panic_message(&*e);
+                    println!("paniced with {message}");
+                    ManagerError::Panic(message)
                 })
-                .and_then(|a| a)
+                .and_then(|a| a.map_err(|err| ManagerError::Failed(Arc::new(err))))
         }
     }
 }
 
-const MAX_RESOURCE_REGEN: Duration = Duration::from_secs(15);
-const MAX_RESOURCE_WAIT: Duration = Duration::from_secs(30);
+// enough to see a good stretch of reconnects without growing forever
+const MAX_STATE_HISTORY: usize = 256;
+
+type RefreshWaiter<E> = oneshot::Sender<Result<(), ManagerError<E>>>;
 
 pub struct ResourceManager<T: Resource> {
     pub resource: Arc<T>,
-    refreshed_at: Mutex<SystemTime>,
-    request_retries: mpsc::Sender<oneshot::Sender<Result<(), Arc<RelayError>>>>,
+    shared: Arc<ManagerShared<T>>,
+    regen_debounce: Duration,
+    wait_timeout: Duration,
+    request_retries: mpsc::UnboundedSender<RefreshWaiter<T::Error>>,
+    // single-slot channels: a signal that's already pending covers any new ones
     retry_signal: mpsc::Sender<()>,
     retry_now_signal: mpsc::Sender<()>,
-    death_signal: Option<mpsc::Sender<()>>,
-    pub generated_signal: broadcast::Sender<()>,
-    pub resource_state: Mutex<ResourceState>,
+    death_signal: mpsc::Sender<()>,
+    loop_task: Mutex<Option<JoinHandle<()>>>,
+}
+
+// what the manager loop works with; kept apart from the manager so the loop doesn't keep it alive
+struct ManagerShared<T: Resource> {
+    resource: Arc<T>,
+    refreshed_at: std::sync::Mutex<Option<Instant>>,
+    flaps: AtomicU64,
+    crash_reports: Option<CrashReportConfig>,
+    gate: Option<watch::Receiver<bool>>,
+    generation: watch::Sender<u64>,
+    resource_state: watch::Sender<ResourceState<T::Error>>,
+    state_history: std::sync::Mutex<VecDeque<StateTransition<T::Error>>>,
+}
+
+// without a shutdown, at least don't leave the loop (and with it the resource) running
+impl<T: Resource> Drop for ResourceManager<T> {
+    fn drop(&mut self) {
+        if let Some(task) = self.loop_task.get_mut().take() {
+            task.abort();
+        }
+    }
+}
+
+// aborts the generated task when the manager loop is dropped along with it
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
 }
 
 impl<T: Resource> Deref for ResourceManager<T> {
@@ -46,97 +153,383 @@ impl<T: Resource> Deref for ResourceManager<T> {
     }
 }
 
-impl<T: Resource> Drop for ResourceManager<T> {
-    fn drop(&mut self) {
-        let my_ref = self.death_signal.take().expect("Death empty; already dropped?");
-        tokio::spawn(async move {
-            my_ref.send(()).await.unwrap()
-        });
-    }
+#[derive(Debug, Error)]
+pub struct ResourceFailure<E: ResourceError> {
+    pub retry_wait: Option<u64>,
+    pub error: ManagerError<E>,
 }
 
-#[derive(Clone, Debug, Error)]
-pub struct ResourceFailure {
-    pub retry_wait: Option<u64>,
-    pub error: Arc<RelayError>,
+impl<E: ResourceError> Clone for ResourceFailure<E> {
+    fn clone(&self) -> Self {
+        ResourceFailure { retry_wait: self.retry_wait, error: self.error.clone() }
+    }
 }
 
-impl Display for ResourceFailure {
+impl<E: ResourceError> Display for ResourceFailure<E> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Failed to generate resource {}; {}", self.error, 
+        write!(f, "Failed to generate resource {}; {}", self.error,
             if let Some(retry_in) = self.retry_wait { format!("retrying in {}s", retry_in) } else { "not retrying".to_string() })
     }
 }
 
 
-#[derive(Clone)]
-pub enum ResourceState {
+#[derive(Debug)]
+pub enum ResourceState<E: ResourceError> {
     Generated,
     Generating,
-    Failed (ResourceFailure)
+    /// Held back by the manager's gate, e.g. until its dependencies are up
+    Waiting,
+    /// The generated resource died or failed its health check; regenerating
+    Lost(ManagerError<E>),
+    Failed (ResourceFailure<E>)
+}
+
+impl<E: ResourceError> Clone for ResourceState<E> {
+    fn clone(&self) -> Self {
+        match self {
+            ResourceState::Generated => ResourceState::Generated,
+            ResourceState::Generating => ResourceState::Generating,
+            ResourceState::Waiting => ResourceState::Waiting,
+            ResourceState::Lost(error) => ResourceState::Lost(error.clone()),
+            ResourceState::Failed(failure) => ResourceState::Failed(failure.clone()),
+        }
+    }
+}
+
+/// One entry in `ResourceManager::state_history`
+#[derive(Debug)]
+pub struct StateTransition<E: ResourceError> {
+    pub state: ResourceState<E>,
+    /// Which generate attempt since the resource was last lost; 0 before the first. `Lost` keeps
+    /// the attempt that generated the resource it lost
+    pub attempt: u32,
+    pub at: SystemTime,
+}
+
+impl<E: ResourceError> Clone for StateTransition<E> {
+    fn clone(&self) -> Self {
+        StateTransition { state: self.state.clone(), attempt: self.attempt, at: self.at }
+    }
+}
+
+/// Configures and starts a `ResourceManager`
+pub struct ResourceManagerBuilder<B: BackoffBuilder> {
+    backoff: B,
+    regen_debounce: Duration,
+    wait_timeout: Duration,
+    health_timeout: Duration,
+    jitter: bool,
+    stable_after: Duration,
+    flap_threshold: u32,
+    health_interval: Option<Duration>,
+    crash_reports: Option<CrashReportConfig>,
+    gate: Option<watch::Receiver<bool>>,
+    running_resource: Option<JoinHandle<()>>,
+}
+
+impl Default for ResourceManagerBuilder<ExponentialBuilder> {
+    fn default() -> Self {
+        ResourceManagerBuilder::new()
+    }
+}
+
+impl ResourceManagerBuilder<ExponentialBuilder> {
+    pub fn new() -> ResourceManagerBuilder<ExponentialBuilder> {
+        ResourceManagerBuilder {
+            backoff: ExponentialBuilder::default(),
+            regen_debounce: Duration::from_secs(15),
+            wait_timeout: Duration::from_secs(30),
+            health_timeout: Duration::from_secs(30),
+            jitter: false,
+            stable_after: Duration::from_secs(60),
+            flap_threshold: 3,
+            health_interval: None,
+            crash_reports: None,
+            gate: None,
+            running_resource: None,
+        }
+    }
+}
+
+impl<B: BackoffBuilder + 'static> ResourceManagerBuilder<B> {
+    /// Delays between failed generate attempts; the manager gives up when it runs out
+    pub fn backoff<B2: BackoffBuilder + 'static>(self, backoff: B2) -> ResourceManagerBuilder<B2> {
+        ResourceManagerBuilder {
+            backoff,
+            regen_debounce: self.regen_debounce,
+            wait_timeout: self.wait_timeout,
+            health_timeout: self.health_timeout,
+            jitter: self.jitter,
+            stable_after: self.stable_after,
+            flap_threshold: self.flap_threshold,
+            health_interval: self.health_interval,
+            crash_reports: self.crash_reports,
+            gate: self.gate,
+            running_resource: self.running_resource,
+        }
+    }
+
+    /// `refresh` is a no-op this soon after the last successful generate
+    pub fn regen_debounce(mut self, regen_debounce: Duration) -> Self {
+        self.regen_debounce = regen_debounce;
+        self
+    }
+
+    /// How long `refresh` waits for the regenerate before giving up
+    pub fn wait_timeout(mut self, wait_timeout: Duration) -> Self {
+        self.wait_timeout = wait_timeout;
+        self
+    }
+
+    /// How long a health check gets before it counts as failed
+    pub fn health_timeout(mut self, health_timeout: Duration) -> Self {
+        self.health_timeout = health_timeout;
+        self
+    }
+
+    /// Scale each retry delay by a random factor between 0.5 and 1.5
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// How long a generated resource has to stay up before the backoff starts over
+    pub fn stable_after(mut self, stable_after: Duration) -> Self {
+        self.stable_after = stable_after;
+        self
+    }
+
+    /// Consecutive short-lived runs before the resource counts as flapping and regenerating is
+    /// throttled with the backoff
+    pub fn flap_threshold(mut self, flap_threshold: u32) -> Self {
+        self.flap_threshold = flap_threshold.max(1);
+        self
+    }
+
+    /// Run `Resource::health_check` this often while the resource is up; off by default
+    pub fn health_interval(mut self, health_interval: Duration) -> Self {
+        self.health_interval = Some(health_interval);
+        self
+    }
+
+    /// Write a report for every panic in `generate` or the generated task
+    pub fn crash_reports(mut self, crash_reports: CrashReportConfig) -> Self {
+        self.crash_reports = Some(crash_reports);
+        self
+    }
+
+    /// Only generate while the gate reads true
+    pub fn gate(mut self, gate: watch::Receiver<bool>) -> Self {
+        self.gate = Some(gate);
+        self
+    }
+
+    /// Start out supervising an already generated resource
+    pub fn running(mut self, running_resource: JoinHandle<()>) -> Self {
+        self.running_resource = Some(running_resource);
+        self
+    }
+
+    pub fn build<T: Resource + 'static>(self, resource: Arc<T>) -> Arc<ResourceManager<T>> {
+        ResourceManager::start(resource, self)
+    }
+}
+
+impl<T: Resource + 'static> ManagerShared<T> {
+    async fn generate(&self, attempt: u32) -> Result<JoinHandle<()>, ManagerError<T::Error>> {
+        if let Some(gate) = &self.gate {
+            let mut gate = gate.clone();
+            if !*gate.borrow_and_update() {
+                self.set_state(ResourceState::Waiting, attempt);
+                // a dropped gate never opens; shutdown still cancels this wait
+                if gate.wait_for(|open| *open).await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+                self.set_state(ResourceState::Generating, attempt);
+            }
+        }
+        let result = self.resource.generate_unwind_safe().await;
+        if let Err(ManagerError::Panic(message)) = &result {
+            self.report_panic("generate", message);
+        }
+        result
+    }
+
+    fn report_panic(&self, source: &str, message: &str) {
+        let Some(crash_reports) = &self.crash_reports else { return };
+        let panic = take_panic(message);
+        let history = self.state_history.lock().unwrap().iter().rev().take(10).rev()
+            .map(|transition| format!("{} attempt {}: {:?}",
+                transition.at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis(), transition.attempt, transition.state))
+            .collect::<Vec<_>>()
+            .join("\n");
+        match crash_reports.write(std::any::type_name::<T>(), source, &panic, &format!("recent states:\n{history}")) {
+            Ok(path) => println!("Wrote crash report to {}", path.display()),
+            Err(err) => println!("Failed to write crash report: {err}"),
+        }
+    }
+
+    fn set_state(&self, state: ResourceState<T::Error>, attempt: u32) {
+        let mut history = self.state_history.lock().unwrap();
+        history.push_back(StateTransition { state: state.clone(), attempt, at: SystemTime::now() });
+        while history.len() > MAX_STATE_HISTORY {
+            history.pop_front();
+        }
+        self.resource_state.send_replace(state);
+    }
 }
 
 impl<T: Resource + 'static> ResourceManager<T> {
-    pub fn new<B: BackoffBuilder + 'static>(resource: Arc<T>, backoff: B, running_resource: Option<JoinHandle<()>>) -> Arc<ResourceManager<T>> {
-        let (retry_send, mut retry_recv) = mpsc::channel::<oneshot::Sender<Result<(), Arc<RelayError>>>>(99999);
-        let (sig_send, mut sig_recv) = mpsc::channel(99999);
-        let (retry_now_send, mut retry_now_recv) = mpsc::channel(99999);
-        let (death_send, mut death_recv) = mpsc::channel(99999);
-        let (generated_send, _) = broadcast::channel(99);
+    fn start<B: BackoffBuilder + 'static>(resource: Arc<T>, config: ResourceManagerBuilder<B>) -> Arc<ResourceManager<T>> {
+        let ResourceManagerBuilder { backoff, regen_debounce, wait_timeout, health_timeout, jitter, stable_after, flap_threshold, health_interval, crash_reports, gate, running_resource } = config;
+        // only with crash reports to put them in; otherwise leave the process's panic hook alone
+        if crash_reports.is_some() {
+            install_panic_hook();
+        }
+        let has_running = running_resource.is_some();
+        let (retry_send, mut retry_recv) = mpsc::unbounded_channel::<RefreshWaiter<T::Error>>();
+        let (sig_send, mut sig_recv) = mpsc::channel(1);
+        let (retry_now_send, mut retry_now_recv) = mpsc::channel(1);
+        let (death_send, mut death_recv) = mpsc::channel(1);
 
+        let initial_state = if running_resource.is_some() { ResourceState::Generated } else { ResourceState::Generating };
+        let shared = Arc::new(ManagerShared {
+            resource: resource.clone(),
+            refreshed_at: std::sync::Mutex::new(None),
+            flaps: AtomicU64::new(0),
+            crash_reports,
+            gate,
+            generation: watch::Sender::new(if has_running { 1 } else { 0 }),
+            resource_state: watch::Sender::new(initial_state.clone()),
+            state_history: std::sync::Mutex::new(VecDeque::from([StateTransition { state: initial_state, attempt: 0, at: SystemTime::now() }])),
+        });
         let manager = Arc::new(ResourceManager {
             resource,
-            refreshed_at: Mutex::new(SystemTime::UNIX_EPOCH),
+            shared: shared.clone(),
+            regen_debounce,
+            wait_timeout,
             request_retries: retry_send,
             retry_signal: sig_send,
             retry_now_signal: retry_now_send,
-            death_signal: Some(death_send),
-            generated_signal: generated_send.clone(),
-            resource_state: Mutex::new(if running_resource.is_some() { ResourceState::Generated } else { ResourceState::Generating }),
+            death_signal: death_send,
+            loop_task: Mutex::new(None),
         });
 
-        let mut current_resource = running_resource.unwrap_or_else(|| tokio::spawn(async {}));
+        let mut current_resource = AbortOnDrop(running_resource.unwrap_or_else(|| tokio::spawn(async {})));
 
-        let loop_manager = manager.clone();
-        tokio::spawn(async move {
-            let mut resolve_items = move |result: Result<(), Arc<RelayError>>, sig_recv: &mut mpsc::Receiver<()>, sig_recv_now: &mut mpsc::Receiver<()>| {
-                while let Ok(_) = sig_recv.try_recv() { }
-                while let Ok(_) = sig_recv_now.try_recv() { }
+        let loop_manager = shared;
+        let loop_task = tokio::spawn(async move {
+            let mut resolve_items = move |result: Result<(), ManagerError<T::Error>>, sig_recv: &mut mpsc::Receiver<()>, sig_recv_now: &mut mpsc::Receiver<()>| {
+                while sig_recv.try_recv().is_ok() { }
+                while sig_recv_now.try_recv().is_ok() { }
                 while let Ok(item) = retry_recv.try_recv() {
                     let _ = item.send(result.clone());
                 }
             };
 
+            let jittered = move |delay: Duration| if jitter { delay.mul_f64(rand::thread_rng().gen_range(0.5..1.5)) } else { delay };
+            // backoff carries over between regenerations until the resource has been up for stable_after
+            let mut backoff_state = backoff.build();
+            let mut generated_at = has_running.then(Instant::now);
+            let mut unstable_runs = 0;
+            // whether current_resource is a generated task that hasn't exited yet
+            let mut running = has_running;
+            // the attempt that generated current_resource
+            let mut attempt = 0;
+
             'stop: loop {
-                select! {
-                    _ = &mut current_resource => {},
-                    _ = sig_recv.recv() => {},
-                    _ = retry_now_recv.recv() => {},
+                let health = async {
+                    let Some(health_interval) = health_interval else { return std::future::pending().await };
+                    loop {
+                        tokio::time::sleep(health_interval).await;
+                        match tokio::time::timeout(health_timeout, loop_manager.resource.health_check()).await {
+                            Ok(Ok(())) => {},
+                            Ok(Err(err)) => return ManagerError::Unhealthy(Arc::new(err)),
+                            Err(_) => return ManagerError::Timeout,
+                        }
+                    }
+                };
+                let (lost, abort_reason) = select! {
+                    result = &mut current_resource.0 => {
+                        let error = match result {
+                            Err(err) if err.is_panic() => {
+                                let message = panic_message(&*err.into_panic());
+                                println!("resource task paniced with {message}");
+                                loop_manager.report_panic("task", &message);
+                                ManagerError::Panic(message)
+                            },
+                            _ => ManagerError::Exited,
+                        };
+                        // the placeholder task of a manager started without a running resource isn't a loss
+                        if running {
+                            loop_manager.set_state(ResourceState::Lost(error), attempt);
+                        }
+                        running = false;
+                        (true, None)
+                    },
+                    err = health => {
+                        println!("resource unhealthy, regenerating: {err}");
+                        loop_manager.resource.unhealthy(&err);
+                        let reason = err.to_string();
+                        loop_manager.set_state(ResourceState::Lost(err), attempt);
+                        (true, Some(reason))
+                    },
+                    _ = sig_recv.recv() => (false, Some("regenerating".to_string())),
+                    _ = retry_now_recv.recv() => (false, Some("regenerating".to_string())),
                     _ = death_recv.recv() => {
                         break // no retries
                     },
+                };
+                if let Some(reason) = abort_reason.filter(|_| running) {
+                    loop_manager.resource.aborted(&reason);
                 }
-                current_resource.abort();
-                let mut backoff = backoff.build();
-                *loop_manager.resource_state.lock().await = ResourceState::Generating;
-                let mut result = loop_manager.resource.generate_unwind_safe().await;
-                while let Err(e) = result {
-
-                    println!("resource failed with {e}");
-                    
-                    let shared_err = Arc::new(e);
-                    resolve_items(Err(shared_err.clone()), &mut sig_recv, &mut retry_now_recv);
-                    let retry_in = backoff.next().unwrap();
-
-                    let is_final = matches!(*shared_err, RelayError::DoNotRetry(_));
-
-                    *loop_manager.resource_state.lock().await = ResourceState::Failed(ResourceFailure {
-                        retry_wait: if !is_final { Some(retry_in.as_secs()) } else { None },
-                        error: shared_err
-                    });
-                    if is_final {
-                        break 'stop;
+                running = false;
+                current_resource.0.abort();
+
+                if generated_at.take().is_none_or(|at| at.elapsed() >= stable_after) {
+                    backoff_state = backoff.build();
+                    unstable_runs = 0;
+                } else if lost {
+                    unstable_runs += 1;
+                    loop_manager.flaps.fetch_add(1, Ordering::Relaxed);
+                    // died soon after coming up; past the threshold, slow down instead of reconnecting at full speed
+                    if unstable_runs >= flap_threshold {
+                        if let Some(delay) = backoff_state.next().map(jittered) {
+                            println!("resource is flapping ({unstable_runs} short runs), waiting {delay:?}");
+                            loop_manager.resource.flapping(unstable_runs, delay);
+                            select! {
+                                _ = tokio::time::sleep(delay) => {},
+                                _ = retry_now_recv.recv() => {},
+                                _ = death_recv.recv() => break 'stop,
+                            };
+                        }
                     }
+                }
+
+                attempt = 1;
+                loop_manager.set_state(ResourceState::Generating, attempt);
+                let mut result = select! {
+                    result = loop_manager.generate(attempt) => result,
+                    _ = death_recv.recv() => break 'stop,
+                };
+                while let Err(err) = result {
+
+                    println!("resource failed with {err}");
+
+                    resolve_items(Err(err.clone()), &mut sig_recv, &mut retry_now_recv);
+                    let retry_in = backoff_state.next().map(jittered);
+
+                    // permanent failures (missing entitlement, unsupported device) won't fix themselves
+                    let retry_in = retry_in.filter(|_| !err.is_final());
+
+                    loop_manager.set_state(ResourceState::Failed(ResourceFailure {
+                        retry_wait: retry_in.map(|retry_in| retry_in.as_secs()),
+                        error: err.clone()
+                    }), attempt);
+                    let Some(retry_in) = retry_in else {
+                        break 'stop;
+                    };
+                    loop_manager.resource.retry_scheduled(&err, retry_in);
                     select! {
                         _ = tokio::time::sleep(retry_in) => {},
                         _ = retry_now_recv.recv() => {},
@@ -144,53 +537,418 @@ impl<T: Resource + 'static> ResourceManager<T> {
                             break 'stop;
                         }
                     };
-                    *loop_manager.resource_state.lock().await = ResourceState::Generating;
-                    result = loop_manager.resource.generate_unwind_safe().await;
+                    attempt += 1;
+                    loop_manager.set_state(ResourceState::Generating, attempt);
+                    result = select! {
+                        result = loop_manager.generate(attempt) => result,
+                        _ = death_recv.recv() => break 'stop,
+                    };
                 }
-                current_resource = result.unwrap();
-                *loop_manager.refreshed_at.lock().await = SystemTime::now();
-                *loop_manager.resource_state.lock().await = ResourceState::Generated;
-                let _ = generated_send.send(());
+                current_resource = AbortOnDrop(result.unwrap());
+                running = true;
+                generated_at = Some(Instant::now());
+                *loop_manager.refreshed_at.lock().unwrap() = generated_at;
+                loop_manager.set_state(ResourceState::Generated, attempt);
+                loop_manager.generation.send_modify(|generation| *generation += 1);
                 resolve_items(Ok(()), &mut sig_recv, &mut retry_now_recv);
             }
+            if running {
+                loop_manager.resource.aborted("shutting down");
+            }
+            current_resource.0.abort();
+            // anyone still waiting on a refresh would otherwise wait out the wait timeout
+            resolve_items(Err(ManagerError::Shutdown), &mut sig_recv, &mut retry_now_recv);
             println!("Resource task closed");
         });
+        // nothing else can hold the lock before `start` returns
+        *manager.loop_task.try_lock().unwrap() = Some(loop_task);
 
         manager
     }
 
-    pub async fn ensure_not_failed(&self) -> Result<(), RelayError> {
-        if let ResourceState::Failed(error) = &*self.resource_state.lock().await {
-            return Err(error.error.clone().into())
+    /// How many times the resource was lost before it had been up for `stable_after`
+    pub fn flaps(&self) -> u64 {
+        self.shared.flaps.load(Ordering::Relaxed)
+    }
+
+    /// How many times the resource has been generated
+    pub fn generation(&self) -> u64 {
+        *self.shared.generation.borrow()
+    }
+
+    /// Notified every time the resource is generated, even if the state never visibly changed
+    pub fn subscribe_generation(&self) -> watch::Receiver<u64> {
+        self.shared.generation.subscribe()
+    }
+
+    pub fn state(&self) -> ResourceState<T::Error> {
+        self.shared.resource_state.borrow().clone()
+    }
+
+    /// Notified on every state transition
+    pub fn subscribe_state(&self) -> watch::Receiver<ResourceState<T::Error>> {
+        self.shared.resource_state.subscribe()
+    }
+
+    /// Recent transitions, oldest first
+    pub fn state_history(&self) -> Vec<StateTransition<T::Error>> {
+        self.shared.state_history.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Wait for the resource to be generated, giving up early if it failed for good
+    pub async fn wait_until_generated(&self, timeout: Duration) -> Result<(), ManagerError<T::Error>> {
+        let mut states = self.subscribe_state();
+        let wait = states.wait_for(|state| match state {
+            ResourceState::Generated => true,
+            ResourceState::Failed(failure) => failure.retry_wait.is_none(),
+            ResourceState::Generating | ResourceState::Waiting | ResourceState::Lost(_) => false,
+        });
+        let state = tokio::time::timeout(timeout, wait).await
+            .map_err(|_| ManagerError::Timeout)?
+            .map_err(|_| ManagerError::Shutdown)?
+            .clone();
+        match state {
+            ResourceState::Failed(failure) => Err(failure.error),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn ensure_not_failed(&self) -> Result<(), ManagerError<T::Error>> {
+        match self.state() {
+            ResourceState::Failed(failure) => Err(failure.error),
+            ResourceState::Lost(error) => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    /// Stop the manager: cancels an in-flight generate, aborts the running resource, fails any
+    /// pending refresh with `ManagerError::Shutdown`, and returns once the manager loop has exited.
+    pub async fn shutdown(&self) {
+        let mut loop_task = self.loop_task.lock().await;
+        let Some(task) = loop_task.take() else { return };
+        // the loop may already be gone after a permanent failure
+        let _ = self.death_signal.try_send(());
+        if let Err(err) = task.await {
+            println!("Resource task failed during shutdown: {err}");
         }
-        Ok(())
     }
 
     pub async fn request_update(&self) {
-        self.retry_signal.send(()).await.unwrap();
+        let _ = self.retry_signal.try_send(());
     }
 
-    pub async fn refresh(&self) -> Result<(), RelayError> {
+    pub async fn refresh(&self) -> Result<(), ManagerError<T::Error>> {
         self.refresh_option(false).await
     }
-    
-    pub async fn refresh_now(&self) -> Result<(), RelayError> {
+
+    pub async fn refresh_now(&self) -> Result<(), ManagerError<T::Error>> {
         self.refresh_option(true).await
     }
 
-    async fn refresh_option(&self, now: bool) -> Result<(), RelayError> {
-        let elapsed = self.refreshed_at.lock().await.elapsed().unwrap();
-        if elapsed < MAX_RESOURCE_REGEN {
+    async fn refresh_option(&self, now: bool) -> Result<(), ManagerError<T::Error>> {
+        if self.shared.refreshed_at.lock().unwrap().is_some_and(|at| at.elapsed() < self.regen_debounce) {
             return Ok(())
         }
         let (send, confirm) = oneshot::channel();
-        self.request_retries.send(send).await.unwrap();
-        if now {
-            self.retry_now_signal.send(()).await.unwrap();
+        // sends only fail once the loop has exited, for good or after a permanent failure
+        if self.request_retries.send(send).is_err() {
+            return Err(match self.state() {
+                ResourceState::Failed(ResourceFailure { retry_wait: None, error }) => error,
+                _ => ManagerError::Shutdown,
+            })
+        }
+        let _ = if now {
+            self.retry_now_signal.try_send(())
         } else {
-            self.retry_signal.send(()).await.unwrap();
+            self.retry_signal.try_send(())
+        };
+        tokio::time::timeout(self.wait_timeout, confirm).await
+            .map_err(|_| ManagerError::Timeout)?
+            .map_err(|_| ManagerError::Shutdown)?
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::{atomic::{AtomicBool, Ordering}, Arc}, time::Duration};
+
+    use backon::{ConstantBuilder, ExponentialBuilder};
+    use tokio::time::Instant;
+
+    use crate::{crash::CrashReportConfig, error::RelayError, testing::{TempDir, TestResource}};
+
+    use super::{ManagerError, ResourceFailure, ResourceManager, ResourceManagerBuilder, ResourceState};
+
+    const MINUTE: Duration = Duration::from_secs(60);
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    fn transient() -> RelayError {
+        RelayError::BadCommand("test failure".to_string())
+    }
+
+    fn manager(resource: Arc<TestResource>, delay: Duration) -> Arc<ResourceManager<TestResource>> {
+        ResourceManagerBuilder::new()
+            .backoff(ConstantBuilder::default().with_delay(delay).with_max_times(usize::MAX))
+            .build(resource)
+    }
+
+    async fn wait_for_failure(manager: &ResourceManager<TestResource>) {
+        manager.subscribe_state().wait_for(|state| matches!(state, ResourceState::Failed(_))).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_with_backoff_until_generated() {
+        let resource = TestResource::failing([transient(), transient()]);
+        let manager = manager(resource.clone(), Duration::from_secs(10));
+        let started = Instant::now();
+
+        manager.wait_until_generated(MINUTE).await.unwrap();
+
+        assert_eq!(resource.attempts(), 3);
+        assert_eq!(started.elapsed(), Duration::from_secs(20));
+        let failed_attempts: Vec<u32> = manager.state_history().iter()
+            .filter(|transition| matches!(transition.state, ResourceState::Failed(_)))
+            .map(|transition| transition.attempt)
+            .collect();
+        assert_eq!(failed_attempts, [1, 2]);
+        assert_eq!(manager.state_history().last().unwrap().attempt, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refresh_within_debounce_does_not_regenerate() {
+        let resource = TestResource::failing([]);
+        let manager = manager(resource.clone(), Duration::from_secs(10));
+        manager.wait_until_generated(MINUTE).await.unwrap();
+
+        manager.refresh().await.unwrap();
+
+        assert_eq!(resource.attempts(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_now_skips_backoff() {
+        let resource = TestResource::failing([transient()]);
+        let manager = manager(resource.clone(), HOUR);
+        wait_for_failure(&manager).await;
+        let started = Instant::now();
+
+        manager.refresh_now().await.unwrap();
+
+        assert_eq!(resource.attempts(), 2);
+        assert!(started.elapsed() < HOUR);
+        assert!(matches!(manager.state(), ResourceState::Generated));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_during_backoff_stops_retrying() {
+        let resource = TestResource::failing((0..100).map(|_| transient()));
+        let manager = manager(resource.clone(), HOUR);
+        wait_for_failure(&manager).await;
+        let started = Instant::now();
+
+        // only a retry-now interrupts the backoff, so this waits until shutdown
+        let waiter = tokio::spawn({
+            let manager = manager.clone();
+            async move { manager.refresh().await }
+        });
+        tokio::task::yield_now().await;
+        manager.shutdown().await;
+
+        assert!(started.elapsed() < HOUR);
+        assert!(matches!(waiter.await.unwrap(), Err(ManagerError::Shutdown)));
+        assert!(matches!(manager.refresh().await, Err(ManagerError::Shutdown)));
+        tokio::time::sleep(2 * HOUR).await;
+        assert_eq!(resource.attempts(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_cancels_in_flight_generate() {
+        let resource = Arc::new(TestResource { hang: true, ..Default::default() });
+        let manager = manager(resource.clone(), HOUR);
+        while resource.attempts() == 0 {
+            tokio::task::yield_now().await;
         }
-        Ok(tokio::time::timeout(MAX_RESOURCE_WAIT, confirm).await.map_err(|_| RelayError::ResourceTimeout)?.unwrap()?)
+
+        manager.shutdown().await;
+
+        assert!(matches!(manager.refresh().await, Err(ManagerError::Shutdown)));
+        assert_eq!(resource.attempts(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn do_not_retry_is_final() {
+        let resource = TestResource::failing([RelayError::DoNotRetry(Box::new(transient()))]);
+        let manager = manager(resource.clone(), Duration::from_secs(1));
+
+        let err = manager.wait_until_generated(MINUTE).await.unwrap_err();
+
+        assert!(matches!(&err, ManagerError::Failed(err) if matches!(**err, RelayError::DoNotRetry(_))));
+        assert!(matches!(manager.state(), ResourceState::Failed(failure) if failure.retry_wait.is_none()));
+        tokio::time::sleep(HOUR).await;
+        assert_eq!(resource.attempts(), 1);
+        assert!(matches!(manager.refresh().await, Err(ManagerError::Failed(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flapping_resource_is_throttled() {
+        let resource = Arc::new(TestResource { lifetime: Some(Duration::from_secs(1)), ..Default::default() });
+        let manager = ResourceManagerBuilder::new()
+            .backoff(ConstantBuilder::default().with_delay(Duration::from_secs(10)).with_max_times(usize::MAX))
+            .flap_threshold(3)
+            .build(resource.clone());
+
+        // up at 0s, 1s and 2s; the third early death at 3s holds the next generate until 13s
+        tokio::time::sleep(Duration::from_millis(12_500)).await;
+        assert_eq!(resource.attempts(), 3);
+        assert_eq!(manager.flaps(), 3);
+        assert_eq!(resource.flaps_reported.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(resource.attempts(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_resets_after_stable_run() {
+        let resource = Arc::new(TestResource {
+            script: std::sync::Mutex::new(VecDeque::from([Some(transient()), Some(transient()), None, Some(transient())])),
+            lifetime: Some(2 * MINUTE),
+            ..Default::default()
+        });
+        let manager = ResourceManagerBuilder::new()
+            .backoff(ExponentialBuilder::default().with_min_delay(Duration::from_secs(1)).with_max_times(usize::MAX))
+            .stable_after(MINUTE)
+            .build(resource.clone());
+
+        tokio::time::sleep(3 * MINUTE).await;
+
+        let retry_waits: Vec<Option<u64>> = manager.state_history().iter()
+            .filter_map(|transition| match &transition.state {
+                ResourceState::Failed(failure) => Some(failure.retry_wait),
+                _ => None,
+            })
+            .collect();
+        // without the reset the third failure would wait 4s
+        assert_eq!(retry_waits, [Some(1), Some(2), Some(1)]);
+        assert_eq!(manager.flaps(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_health_check_regenerates() {
+        let resource = Arc::new(TestResource::default());
+        let manager = ResourceManagerBuilder::new()
+            .health_interval(Duration::from_secs(10))
+            .build(resource.clone());
+        manager.wait_until_generated(MINUTE).await.unwrap();
+
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        assert_eq!(resource.attempts(), 1);
+
+        resource.sick.store(true, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(resource.attempts(), 2);
+        assert!(matches!(manager.state(), ResourceState::Generated));
+        assert!(manager.state_history().iter().any(|transition| matches!(
+            &transition.state,
+            ResourceState::Lost(ManagerError::Unhealthy(err)) if matches!(**err, RelayError::RelayUnresponsive(_))
+        ) && transition.attempt == 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hung_health_check_times_out() {
+        let resource = Arc::new(TestResource::default());
+        let manager = ResourceManagerBuilder::new()
+            .health_interval(Duration::from_secs(10))
+            .health_timeout(Duration::from_secs(5))
+            .build(resource.clone());
+        manager.wait_until_generated(MINUTE).await.unwrap();
+
+        resource.stuck.store(true, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_secs(12)).await;
+        assert_eq!(resource.attempts(), 1);
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(resource.attempts(), 2);
+        assert!(manager.state_history().iter().any(|transition| matches!(transition.state, ResourceState::Lost(ManagerError::Timeout))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_task_that_returns_is_lost_before_the_retry() {
+        let resource = Arc::new(TestResource { lifetime: Some(MINUTE), ..Default::default() });
+        let manager = ResourceManagerBuilder::new()
+            .stable_after(Duration::from_secs(30))
+            .build(resource.clone());
+
+        // generated at 0s, returns at 60s, generated again right away
+        tokio::time::sleep(MINUTE + MINUTE / 2).await;
+        assert_eq!(resource.attempts(), 2);
+        let history: Vec<_> = manager.state_history().iter()
+            .map(|transition| (format!("{:?}", transition.state), transition.attempt))
+            .collect();
+        assert_eq!(history, [("Generating", 0), ("Generating", 1), ("Generated", 1), ("Lost(Exited)", 1), ("Generating", 1), ("Generated", 1)]
+            .map(|(state, attempt)| (state.to_string(), attempt)));
     }
 
-}
\ No newline at end of file
+    #[tokio::test(start_paused = true)]
+    async fn aborting_a_running_resource_is_reported() {
+        let resource = Arc::new(TestResource { lifetime: Some(MINUTE), ..Default::default() });
+        let manager = ResourceManagerBuilder::new()
+            .regen_debounce(Duration::ZERO)
+            .build(resource.clone());
+        manager.wait_until_generated(MINUTE).await.unwrap();
+
+        manager.refresh_now().await.unwrap();
+        // exiting on its own isn't an abort
+        tokio::time::sleep(MINUTE + MINUTE / 2).await;
+        assert_eq!(resource.attempts(), 3);
+        manager.shutdown().await;
+
+        assert_eq!(*resource.aborts.lock().unwrap(), ["regenerating", "shutting down"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropping_the_manager_stops_the_resource() {
+        let resource = Arc::new(TestResource::default());
+        let manager = manager(resource.clone(), MINUTE);
+        manager.wait_until_generated(MINUTE).await.unwrap();
+        assert_eq!(Arc::strong_count(&resource.live), 2);
+
+        drop(manager);
+        tokio::time::sleep(MINUTE).await;
+        assert_eq!(Arc::strong_count(&resource.live), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn panics_are_captured_and_reported() {
+        let dir = TempDir::new("crash-test");
+        let resource = Arc::new(TestResource {
+            panic_generate: AtomicBool::new(true),
+            panic_task: AtomicBool::new(true),
+            ..Default::default()
+        });
+        let manager = ResourceManagerBuilder::new()
+            .backoff(ConstantBuilder::default().with_delay(Duration::from_secs(1)).with_max_times(usize::MAX))
+            .crash_reports(CrashReportConfig { dir: dir.path().to_path_buf(), keep: 10 })
+            .build(resource.clone());
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(resource.attempts(), 3);
+        let panics: Vec<(u32, String)> = manager.state_history().into_iter()
+            .filter_map(|transition| match transition.state {
+                ResourceState::Failed(ResourceFailure { error: ManagerError::Panic(message), .. })
+                | ResourceState::Lost(ManagerError::Panic(message)) => Some((transition.attempt, message)),
+                _ => None,
+            })
+            .collect();
+        // the task that panicked came from the second attempt of the first run
+        assert_eq!(panics, [(1, "generate panicked on attempt 1".to_string()), (2, "task panicked on attempt 2".to_string())]);
+
+        let reports: Vec<String> = std::fs::read_dir(dir.path()).unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect();
+        assert_eq!(reports.len(), 2);
+        for report in &reports {
+            assert!(report.contains("location: src/testing.rs"), "{report}");
+            assert!(report.contains("backtrace:\n") && !report.contains("not captured"), "{report}");
+        }
+    }
+}